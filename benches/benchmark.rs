@@ -1,18 +1,21 @@
 use collabori::crdt::RGA;
-use collabori::data::Operation;
+use collabori::data::{ElementId, Operation};
 use collabori::ot::OT;
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use std::hint::black_box;
 
 fn bench_transform(c: &mut Criterion) {
     let op_a = Operation::Insert {
         index: 1,
         value: 'a',
-        id: "1".into(),
+        id: ElementId::new(1, 1),
+        origin: None,
     };
     let op_b = Operation::Insert {
         index: 2,
         value: 'b',
-        id: "2".into(),
+        id: ElementId::new(2, 1),
+        origin: None,
     };
 
     c.bench_function("OT Transform Insert Insert", |b| {
@@ -32,12 +35,18 @@ fn bench_crdt_insert(c: &mut Criterion) {
 }
 
 fn bench_crdt_delete(c: &mut Criterion) {
-    let mut rga = RGA::new();
-    rga.insert(0, 'a');
     c.bench_function("CRDT Delete", |b| {
-        b.iter(|| {
-            let _ = rga.delete(black_box(0));
-        })
+        b.iter_batched(
+            || {
+                let mut rga = RGA::new();
+                rga.insert(0, 'a');
+                rga
+            },
+            |mut rga| {
+                let _ = rga.delete(black_box(0));
+            },
+            BatchSize::SmallInput,
+        )
     });
 }
 
@@ -53,11 +62,89 @@ fn bench_crdt_merge(c: &mut Criterion) {
     });
 }
 
+const LARGE_DOCUMENT: usize = 100_000;
+
+/// Builds a document of `LARGE_DOCUMENT` characters with every tenth one deleted
+fn large_document(replica_id: u64) -> RGA {
+    let mut rga = RGA::with_replica_id(replica_id);
+    for i in 0..LARGE_DOCUMENT {
+        rga.insert(i, char::from(b'a' + (i % 26) as u8));
+    }
+    for i in (0..LARGE_DOCUMENT / 10).rev() {
+        rga.delete(i * 9);
+    }
+    rga
+}
+
+fn bench_crdt_large_document(c: &mut Criterion) {
+    let mut rga = large_document(1);
+    let mid = rga.len() / 2;
+    c.bench_function("CRDT Insert Middle 100k", |b| {
+        b.iter(|| {
+            let _ = rga.insert(black_box(mid), black_box('x'));
+        })
+    });
+
+    let mut rga = large_document(1);
+    let mid = rga.len() / 2;
+    c.bench_function("CRDT Delete+Insert Middle 100k", |b| {
+        b.iter(|| {
+            let _ = rga.delete(black_box(mid));
+            let _ = rga.insert(black_box(mid), black_box('x'));
+        })
+    });
+
+    let rga = large_document(1);
+    let id = rga.id_at(rga.len() * 3 / 4).unwrap();
+    c.bench_function("CRDT Lookup By Id 100k", |b| {
+        b.iter(|| {
+            let _ = rga.index_of(black_box(&id));
+        })
+    });
+
+    let mut local = large_document(1);
+    let mut remote = local.clone();
+    remote.replica_id = 2;
+    let mid = remote.len() / 2;
+    c.bench_function("CRDT Apply Remote Insert 100k", |b| {
+        b.iter_batched(
+            || remote.insert(mid, 'r'),
+            |op| local.apply(black_box(&op)),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn bench_crdt_large_merge(c: &mut Criterion) {
+    let base = large_document(1);
+    let mut local = base.clone();
+    let mut remote = base;
+    remote.replica_id = 2;
+    for i in 0..1_000 {
+        local.insert(i * 50, 'l');
+        remote.insert(i * 70, 'r');
+        remote.delete(i * 30 + 1);
+    }
+
+    let mut group = c.benchmark_group("large_document");
+    group.sample_size(10);
+    group.bench_function("CRDT Merge 100k", |b| {
+        b.iter_batched(
+            || (local.clone(), remote.clone()),
+            |(mut local, remote)| local.merge(black_box(remote)),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
 criterion_group!(
     benches,
     bench_transform,
     bench_crdt_insert,
     bench_crdt_delete,
-    bench_crdt_merge
+    bench_crdt_merge,
+    bench_crdt_large_document,
+    bench_crdt_large_merge
 );
 criterion_main!(benches);
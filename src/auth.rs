@@ -0,0 +1,99 @@
+use crate::errors::CollaboriError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+/// What a user may do with a document, from least to most trusted
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Viewer,
+    Commenter,
+    Editor,
+    Owner,
+}
+
+impl Role {
+    /// Returns true if the role may change the document's text
+    pub fn can_edit(self) -> bool {
+        self >= Role::Editor
+    }
+}
+
+/// The user a connection was authenticated as
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Identity {
+    pub user_id: String,
+}
+
+/// Decides who may connect to a `SyncManager` and what they may do.
+///
+/// `authenticate` runs during the WebSocket handshake with the bearer token
+/// from the `Authorization` header or the `access_token` query parameter;
+/// an error answers the handshake with 401. `role` runs whenever the
+/// connection enters a document's room; an error keeps it out.
+pub trait Authenticator: Send + Sync + Debug {
+    fn authenticate(&self, token: Option<&str>) -> Result<Identity, CollaboriError>;
+
+    fn role(&self, user: &Identity, document: &str) -> Result<Role, CollaboriError>;
+}
+
+/// An [`Authenticator`] with a fixed set of tokens and grants
+#[derive(Debug, Clone, Default)]
+pub struct StaticAuthenticator {
+    tokens: HashMap<String, String>,        // Token -> user id
+    roles: HashMap<(String, String), Role>, // (user id, document) -> role
+    default_role: Option<Role>,             // Role in documents without a grant
+}
+
+impl StaticAuthenticator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accepts `token` as `user_id`
+    pub fn with_token(mut self, token: &str, user_id: &str) -> Self {
+        self.tokens.insert(token.to_string(), user_id.to_string());
+        self
+    }
+
+    /// Grants `user_id` a role in `document`
+    pub fn with_role(mut self, user_id: &str, document: &str, role: Role) -> Self {
+        self.roles
+            .insert((user_id.to_string(), document.to_string()), role);
+        self
+    }
+
+    /// Grants every authenticated user `role` in documents they have no
+    /// explicit role in
+    pub fn with_default_role(mut self, role: Role) -> Self {
+        self.default_role = Some(role);
+        self
+    }
+}
+
+impl Authenticator for StaticAuthenticator {
+    fn authenticate(&self, token: Option<&str>) -> Result<Identity, CollaboriError> {
+        let token =
+            token.ok_or_else(|| CollaboriError::Unauthorized("missing token".to_string()))?;
+        match self.tokens.get(token) {
+            Some(user_id) => Ok(Identity {
+                user_id: user_id.clone(),
+            }),
+            None => Err(CollaboriError::Unauthorized("unknown token".to_string())),
+        }
+    }
+
+    fn role(&self, user: &Identity, document: &str) -> Result<Role, CollaboriError> {
+        self.roles
+            .get(&(user.user_id.clone(), document.to_string()))
+            .copied()
+            .or(self.default_role)
+            .ok_or_else(|| {
+                CollaboriError::Unauthorized(format!(
+                    "{} has no access to {}",
+                    user.user_id, document
+                ))
+            })
+    }
+}
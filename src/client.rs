@@ -1,129 +1,744 @@
-use crate::data::Operation;
+use crate::crdt::RGA;
+use crate::data::{Awareness, ElementId, Operation, VersionVector};
+use crate::errors::CollaboriError;
+use crate::ot::TextOperation;
+use crate::protocol::{
+    Codec, DocumentSnapshot, Edit, Envelope, Heartbeat, DEFAULT_MAX_MISSED_PINGS,
+};
+use crate::utils::jitter;
 use futures_util::{SinkExt, StreamExt};
-use tokio::sync::mpsc;
-use tokio_tungstenite::connect_async;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::header::{HeaderValue, AUTHORIZATION};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
 use url::Url;
 
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
 #[derive(Debug)]
 pub struct SyncClient {
     pub sender: mpsc::Sender<Operation>, // For sending operations to the server
     pub receiver: mpsc::Receiver<Operation>, // For receiving operations from the server
+    pub snapshot: RGA, // The server's replica when we joined, to merge into the local one
+    pub acked: watch::Receiver<Option<ElementId>>, // `last_id` of the last operation the server accepted
+    pub version: u32,                              // Protocol version negotiated with the server
+    pub events: broadcast::Receiver<ConnectionEvent>, // Connection state changes
+    pub peers: watch::Receiver<HashMap<u64, Awareness>>, // Awareness states of the others in the room
+    pub latency: watch::Receiver<Option<Duration>>, // Round trip of the last answered ping, if connected
+    awareness: watch::Sender<Option<Awareness>>,
+}
+
+/// A change in a [`SyncClient`]'s connection to the server
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionEvent {
+    /// The handshake completed, initially or after reconnecting
+    Connected {
+        version: u32,
+    },
+    Disconnected {
+        reason: String,
+    },
+    /// Waiting `delay` before reconnection attempt number `attempt`
+    Reconnecting {
+        attempt: u32,
+        delay: Duration,
+    },
+    /// Caught up after reconnecting, or after falling behind the server's
+    /// room: `missed` remote operations were delivered on the receiver and
+    /// `resent` unacknowledged local ones sent again
+    Resynced {
+        missed: usize,
+        resent: usize,
+    },
+    /// Every reconnection attempt failed, or the server no longer accepts
+    /// the client's token; operations sent from now on are dropped
+    GaveUp,
+}
+
+/// How a [`SyncClient`] retries after losing its connection.
+///
+/// Attempt `n` waits between half and all of `initial_delay * 2^(n - 1)`,
+/// capped at `max_delay`, so clients dropped together don't retry together.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Failed attempts in a row before giving up, `None` to retry forever
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Returns how long to wait before attempt `attempt`, counting from 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        let backoff = self
+            .initial_delay
+            .saturating_mul(factor)
+            .min(self.max_delay);
+        backoff / 2 + jitter(backoff / 2)
+    }
+}
+
+/// Options for [`SyncClient::connect_with_config`]
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub codec: Codec,
+    pub reconnect: ReconnectPolicy,
+    /// Bearer token sent to servers that require authentication
+    pub token: Option<String>,
+    /// How often the awareness state is resent so the server doesn't time it
+    /// out; keep it below the server's presence timeout
+    pub presence_refresh: Duration,
+    /// How often the server is pinged, `None` to never
+    pub ping_interval: Option<Duration>,
+    /// Pings the server may leave unanswered in a row before the client
+    /// drops the connection and reconnects
+    pub max_missed_pings: u32,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            codec: Codec::default(),
+            reconnect: ReconnectPolicy::default(),
+            token: None,
+            presence_refresh: Duration::from_secs(10),
+            ping_interval: Some(Duration::from_secs(15)),
+            max_missed_pings: DEFAULT_MAX_MISSED_PINGS,
+        }
+    }
 }
 
 impl SyncClient {
     /// Connects to the synchronization server
-    pub async fn connect(addr: &str) -> Self {
+    pub async fn connect(addr: &str) -> Result<Self, CollaboriError> {
+        Self::connect_with_config(addr, ClientConfig::default()).await
+    }
+
+    /// Connects to the synchronization server, exchanging messages in
+    /// `codec`'s format
+    pub async fn connect_with_codec(addr: &str, codec: Codec) -> Result<Self, CollaboriError> {
+        let config = ClientConfig {
+            codec,
+            ..ClientConfig::default()
+        };
+        Self::connect_with_config(addr, config).await
+    }
+
+    /// Connects to the synchronization server. If the connection drops, the
+    /// client reconnects according to `config.reconnect`, queueing the
+    /// operations sent meanwhile.
+    pub async fn connect_with_config(
+        addr: &str,
+        config: ClientConfig,
+    ) -> Result<Self, CollaboriError> {
         // Parse the WebSocket URL
-        let url = Url::parse(&format!("ws://{}", addr)).unwrap();
+        let url = Url::parse(&format!("ws://{}", addr))?;
 
-        // Establish the WebSocket connection
-        let (ws_stream, _) = connect_async(url.as_str())
-            .await
-            .expect("Failed to connect");
-        let (mut write, mut read) = ws_stream.split();
+        // Establish the WebSocket connection and agree on a protocol version,
+        // after which the server sends the document
+        let (ws_stream, version, snapshot) = open(&url, &config).await?;
 
         // Create channels for sending and receiving operations
-        let (send_tx, mut send_rx) = mpsc::channel::<Operation>(100); // Sender to send ops to server
+        let (send_tx, send_rx) = mpsc::channel::<Operation>(100); // Sender to send ops to server
         let (recv_tx, recv_rx) = mpsc::channel::<Operation>(100); // Receiver to receive ops from server
+        let (acked_tx, acked_rx) = watch::channel(None);
+        let (events_tx, events_rx) = broadcast::channel(100);
+        let (awareness_tx, awareness_rx) = watch::channel(None);
+        let (peers_tx, peers_rx) = watch::channel(HashMap::new());
+        let (latency_tx, latency_rx) = watch::channel(None);
+        let _ = events_tx.send(ConnectionEvent::Connected { version });
+
+        let connection = Connection {
+            url,
+            config,
+            send_rx,
+            recv_tx,
+            acked_tx,
+            events_tx,
+            awareness: awareness_rx,
+            peers_tx,
+            latency_tx,
+            unacked: VecDeque::new(),
+            seen: snapshot.version().clone(),
+        };
+        tokio::spawn(connection.run(ws_stream));
 
-        // Spawn a task to handle sending operations to the server
-        tokio::spawn(async move {
-            while let Some(op) = send_rx.recv().await {
-                let msg = serde_json::to_string(&op).unwrap();
-                if write
-                    .send(tokio_tungstenite::tungstenite::Message::Text(msg))
-                    .await
-                    .is_err()
-                {
-                    // If sending fails, exit the loop
-                    println!("Failed to send message to server. Exiting send task.");
-                    break;
+        Ok(SyncClient {
+            sender: send_tx,
+            receiver: recv_rx,
+            snapshot,
+            acked: acked_rx,
+            version,
+            events: events_rx,
+            peers: peers_rx,
+            latency: latency_rx,
+            awareness: awareness_tx,
+        })
+    }
+
+    /// Shows `state` to the other clients in the room, or hides this client
+    /// with `None`. The state is kept across reconnections.
+    pub fn set_awareness(&self, state: Option<Awareness>) {
+        self.awareness.send_replace(state);
+    }
+
+    /// Sends an operation to the server, or queues it until the client has
+    /// reconnected. Fails once the client has given up reconnecting.
+    pub async fn send_operation(&self, op: Operation) -> Result<(), CollaboriError> {
+        self.sender
+            .send(op)
+            .await
+            .map_err(|_| CollaboriError::ChannelClosed("the client stopped reconnecting"))
+    }
+}
+
+/// Opens a connection and completes the handshake, returning the negotiated
+/// version and the server's replica
+async fn open(url: &Url, config: &ClientConfig) -> Result<(WsStream, u32, RGA), CollaboriError> {
+    let codec = config.codec;
+    let mut request = url.as_str().into_client_request()?;
+    if let Some(token) = &config.token {
+        let value = HeaderValue::from_str(&format!("Bearer {}", token))
+            .map_err(|_| CollaboriError::Unauthorized("malformed token".to_string()))?;
+        request.headers_mut().insert(AUTHORIZATION, value);
+    }
+    let mut ws_stream = match connect_async(request).await {
+        Ok((ws_stream, _)) => ws_stream,
+        Err(WsError::Http(response)) if response.status() == StatusCode::UNAUTHORIZED => {
+            let reason = response
+                .body()
+                .as_deref()
+                .map(String::from_utf8_lossy)
+                .unwrap_or_default();
+            return Err(CollaboriError::Unauthorized(reason.into_owned()));
+        }
+        Err(err) => return Err(err.into()),
+    };
+    ws_stream.send(codec.encode(&Envelope::hello())).await?;
+    let mut version = None;
+    loop {
+        let envelope = match ws_stream.next().await {
+            Some(Ok(Message::Close(_))) | None => {
+                return Err(CollaboriError::ProtocolViolation(
+                    "connection closed during the handshake".to_string(),
+                ))
+            }
+            Some(Err(err)) => return Err(err.into()),
+            Some(Ok(msg)) => match Codec::decode(&msg) {
+                Some(envelope) => envelope?,
+                None => continue,
+            },
+        };
+        match (envelope, version) {
+            (
+                Envelope::Hello {
+                    version: chosen, ..
+                },
+                None,
+            ) => version = Some(chosen),
+            (
+                Envelope::Snapshot {
+                    state: DocumentSnapshot::Crdt(rga),
+                    ..
+                },
+                Some(version),
+            ) => return Ok((ws_stream, version, *rga)),
+            (Envelope::Error { code, message }, _) => {
+                return Err(CollaboriError::HandshakeRejected { code, message })
+            }
+            (other, _) => {
+                return Err(CollaboriError::ProtocolViolation(format!(
+                    "unexpected message during the handshake: {:?}",
+                    other
+                )))
+            }
+        }
+    }
+}
+
+/// The task behind a [`SyncClient`], owning its socket across reconnections
+struct Connection {
+    url: Url,
+    config: ClientConfig,
+    send_rx: mpsc::Receiver<Operation>,
+    recv_tx: mpsc::Sender<Operation>,
+    acked_tx: watch::Sender<Option<ElementId>>,
+    events_tx: broadcast::Sender<ConnectionEvent>,
+    awareness: watch::Receiver<Option<Awareness>>,
+    peers_tx: watch::Sender<HashMap<u64, Awareness>>,
+    latency_tx: watch::Sender<Option<Duration>>,
+    unacked: VecDeque<Operation>, // Sent or queued operations awaiting an ack, in order
+    seen: VersionVector,          // Every operation delivered to or sent by the application
+}
+
+impl Connection {
+    async fn run(mut self, mut ws_stream: WsStream) {
+        loop {
+            match self.session(ws_stream).await {
+                Some(reason) => {
+                    // Peers are sent again on reconnecting, under new ids
+                    self.peers_tx.send_replace(HashMap::new());
+                    self.latency_tx.send_replace(None);
+                    self.emit(ConnectionEvent::Disconnected { reason })
                 }
+                // The SyncClient was dropped
+                None => return,
             }
-            println!("Send task has been terminated.");
-        });
+            ws_stream = match self.reconnect().await {
+                Some(ws_stream) => ws_stream,
+                None => return,
+            };
+        }
+    }
 
-        // Spawn a task to handle receiving operations from the server
-        tokio::spawn(async move {
-            while let Some(msg) = read.next().await {
-                match msg {
-                    Ok(tokio_tungstenite::tungstenite::Message::Text(text)) => {
-                        if let Ok(op) = serde_json::from_str::<Operation>(&text) {
-                            println!("Received operation: {:?}", op);
-                            let _ = recv_tx.send(op).await; // Send received op to the receiver channel
-                        }
+    fn emit(&self, event: ConnectionEvent) {
+        let _ = self.events_tx.send(event);
+    }
+
+    /// Records an operation from the application until the server acks it
+    fn queue(&mut self, op: Operation) {
+        self.seen.observe(op.last_id());
+        self.unacked.push_back(op);
+    }
+
+    /// Relays messages until the connection drops, returning why, or `None`
+    /// once the application has dropped the client
+    async fn session(&mut self, mut ws_stream: WsStream) -> Option<String> {
+        let codec = self.config.codec;
+        let period = self.config.presence_refresh;
+        let mut refresh = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        let mut heartbeat = Heartbeat::new(self.config.ping_interval, self.config.max_missed_pings);
+        // Show the current state on the new connection
+        if self.awareness.borrow().is_some() {
+            self.awareness.mark_changed();
+        }
+        loop {
+            tokio::select! {
+                Ok(()) = self.awareness.changed() => {
+                    let state = self.awareness.borrow_and_update().clone();
+                    let envelope = Envelope::Presence { client: None, state };
+                    if let Err(err) = ws_stream.send(codec.encode(&envelope)).await {
+                        return Some(err.to_string());
                     }
-                    Ok(tokio_tungstenite::tungstenite::Message::Close(_)) => {
-                        println!("Received close message from server.");
-                        break;
+                }
+                _ = refresh.tick() => {
+                    let Some(state) = self.awareness.borrow().clone() else {
+                        continue;
+                    };
+                    let envelope = Envelope::Presence { client: None, state: Some(state) };
+                    if let Err(err) = ws_stream.send(codec.encode(&envelope)).await {
+                        return Some(err.to_string());
+                    }
+                }
+                ping = heartbeat.tick() => {
+                    let Some(nonce) = ping else {
+                        return Some(format!("the server missed {} heartbeats", heartbeat.missed()));
+                    };
+                    if let Err(err) = ws_stream.send(codec.encode(&Envelope::Ping { nonce })).await {
+                        return Some(err.to_string());
                     }
-                    Err(e) => {
-                        println!("Error receiving message: {:?}", e);
-                        break;
+                }
+                op = self.send_rx.recv() => {
+                    let Some(op) = op else {
+                        let _ = ws_stream.send(Message::Close(None)).await;
+                        return None;
+                    };
+                    self.queue(op.clone());
+                    let envelope = Envelope::Op { edit: Edit::Crdt(op) };
+                    if let Err(err) = ws_stream.send(codec.encode(&envelope)).await {
+                        return Some(err.to_string());
+                    }
+                }
+                msg = ws_stream.next() => {
+                    let msg = match msg {
+                        Some(Ok(Message::Close(Some(frame)))) => {
+                            return Some(format!(
+                                "connection closed by the server ({}): {}",
+                                frame.code, frame.reason
+                            ))
+                        }
+                        Some(Ok(Message::Close(None))) | None => {
+                            return Some("connection closed by the server".to_string())
+                        }
+                        Some(Err(err)) => return Some(err.to_string()),
+                        Some(Ok(msg)) => msg,
+                    };
+                    heartbeat.heard();
+                    match Codec::decode(&msg) {
+                        None => {} // Ignore other message types
+                        Some(Ok(Envelope::Op {
+                            edit: Edit::Crdt(op),
+                        })) => {
+                            self.seen.observe(op.last_id());
+                            let _ = self.recv_tx.send(op).await; // Send received op to the receiver channel
+                        }
+                        Some(Ok(Envelope::Ack { id: Some(id), .. })) => {
+                            // Acks arrive in the order the operations were
+                            // sent, naming each by its last id since deletes
+                            // share their element's id
+                            let acked = |op: &Operation| op.last_id() == id;
+                            if let Some(pos) = self.unacked.iter().position(acked) {
+                                self.unacked.drain(..=pos);
+                            }
+                            let _ = self.acked_tx.send(Some(id));
+                        }
+                        Some(Ok(Envelope::Snapshot {
+                            state: DocumentSnapshot::Crdt(snapshot),
+                            ..
+                        })) => {
+                            // The client fell behind the room and the server
+                            // resent the document, followed by the peers
+                            self.peers_tx.send_replace(HashMap::new());
+                            let missed = self.catch_up(&snapshot).await;
+                            self.emit(ConnectionEvent::Resynced { missed, resent: 0 });
+                        }
+                        Some(Ok(Envelope::Presence {
+                            client: Some(client),
+                            state,
+                        })) => {
+                            self.peers_tx.send_modify(|peers| match state {
+                                Some(state) => {
+                                    peers.insert(client, state);
+                                }
+                                None => {
+                                    peers.remove(&client);
+                                }
+                            });
+                        }
+                        Some(Ok(Envelope::Ping { nonce })) => {
+                            let pong = codec.encode(&Envelope::Pong { nonce });
+                            if let Err(err) = ws_stream.send(pong).await {
+                                return Some(err.to_string());
+                            }
+                        }
+                        Some(Ok(Envelope::Pong { nonce })) => {
+                            if let Some(latency) = heartbeat.pong(nonce) {
+                                self.latency_tx.send_replace(Some(latency));
+                            }
+                        }
+                        Some(Ok(Envelope::Error { code, message })) => {
+                            println!("Server reported an error ({:?}): {}", code, message);
+                        }
+                        Some(Ok(other)) => println!("Ignoring message: {:?}", other),
+                        Some(Err(e)) => println!("Failed to decode message: {}", e),
                     }
-                    _ => {} // Ignore other message types
                 }
             }
-            println!("Receive task has been terminated.");
+        }
+    }
+
+    /// Reconnects with backoff, queueing operations while offline. Returns
+    /// `None` if the client was dropped or the policy gave up
+    async fn reconnect(&mut self) -> Option<WsStream> {
+        let policy = self.config.reconnect.clone();
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            if policy.max_attempts.is_some_and(|max| attempt > max) {
+                self.emit(ConnectionEvent::GaveUp);
+                return None;
+            }
+            let delay = policy.delay(attempt);
+            self.emit(ConnectionEvent::Reconnecting { attempt, delay });
+            let sleep = tokio::time::sleep(delay);
+            tokio::pin!(sleep);
+            loop {
+                tokio::select! {
+                    _ = &mut sleep => break,
+                    op = self.send_rx.recv() => match op {
+                        Some(op) => self.queue(op),
+                        None => return None,
+                    },
+                }
+            }
+
+            let (mut ws_stream, version, snapshot) = match open(&self.url, &self.config).await {
+                Ok(opened) => opened,
+                // Retrying with the same token won't help
+                Err(err @ CollaboriError::Unauthorized(_)) => {
+                    println!("Reconnection refused: {}", err);
+                    self.emit(ConnectionEvent::GaveUp);
+                    return None;
+                }
+                Err(err) => {
+                    println!("Reconnection attempt {} failed: {}", attempt, err);
+                    continue;
+                }
+            };
+            self.emit(ConnectionEvent::Connected { version });
+            match self.resync(&mut ws_stream, snapshot).await {
+                Ok(()) => return Some(ws_stream),
+                Err(err) => self.emit(ConnectionEvent::Disconnected {
+                    reason: err.to_string(),
+                }),
+            }
+        }
+    }
+
+    /// Catches up with the server's replica after reconnecting: delivers the
+    /// remote operations missed while offline and resends the local ones the
+    /// server hasn't applied
+    async fn resync(
+        &mut self,
+        ws_stream: &mut WsStream,
+        snapshot: RGA,
+    ) -> Result<(), CollaboriError> {
+        let missed = self.catch_up(&snapshot).await;
+        for op in &self.unacked {
+            let envelope = Envelope::Op {
+                edit: Edit::Crdt(op.clone()),
+            };
+            ws_stream.send(self.config.codec.encode(&envelope)).await?;
+        }
+        self.emit(ConnectionEvent::Resynced {
+            missed,
+            resent: self.unacked.len(),
         });
+        Ok(())
+    }
 
-        // Return the SyncClient instance with sender and receiver
-        SyncClient {
-            sender: send_tx,
-            receiver: recv_rx,
+    /// Acks the local operations `snapshot` includes and delivers the remote
+    /// ones the application hasn't seen, returning how many
+    async fn catch_up(&mut self, snapshot: &RGA) -> usize {
+        // The server applies a connection's operations in order, so those it
+        // already has before the connection dropped form a prefix
+        let mut applied = None;
+        while let Some(op) = self.unacked.front() {
+            if !snapshot.version().includes(&op.last_id()) {
+                break;
+            }
+            applied = self.unacked.pop_front();
+        }
+        if let Some(op) = applied {
+            let _ = self.acked_tx.send(Some(op.last_id()));
+        }
+
+        let missed = snapshot.operations_since(&self.seen);
+        for op in &missed {
+            self.seen.observe(op.last_id());
+            let _ = self.recv_tx.send(op.clone()).await;
         }
+        missed.len()
     }
+}
 
-    /// Sends an operation to the server
-    pub async fn send_operation(&self, op: Operation) {
-        self.sender
-            .send(op)
-            .await
-            .expect("Failed to send operation");
+/// Where an [`OTClient`] stands relative to the server
+#[derive(Debug, Clone, PartialEq)]
+pub enum OTState {
+    /// Every local edit has been acknowledged
+    Synchronized,
+    /// One operation has been sent and not yet acknowledged
+    AwaitingAck(TextOperation),
+    /// Edits made while awaiting the ack, composed into one operation that
+    /// is sent once the outstanding one is acknowledged
+    AwaitingWithBuffer(TextOperation, TextOperation),
+}
+
+/// Client side of a revision-based OT server ([`crate::sync::SyncMode::Ot`]).
+///
+/// Keeps a local copy of the document, at most one operation in flight and a
+/// buffer of edits made meanwhile. Operations from the server are transformed
+/// past both before being applied, so the editor can apply them as returned.
+#[derive(Debug, Clone)]
+pub struct OTClient {
+    revision: usize,
+    text: String,
+    state: OTState,
+}
+
+impl OTClient {
+    /// Starts from the document the server sent on joining
+    pub fn new(revision: usize, text: &str) -> Self {
+        OTClient {
+            revision,
+            text: text.to_string(),
+            state: OTState::Synchronized,
+        }
+    }
+
+    /// Returns the last server revision this client has seen
+    pub fn revision(&self) -> usize {
+        self.revision
+    }
+
+    /// Returns the local document, including unacknowledged edits
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn state(&self) -> &OTState {
+        &self.state
+    }
+
+    /// Applies a local edit, returning the message to send if nothing else
+    /// is awaiting an ack.
+    pub fn apply_local(
+        &mut self,
+        operation: TextOperation,
+    ) -> Result<Option<Envelope>, CollaboriError> {
+        let text = operation.apply(&self.text)?;
+        let (state, message) = match &self.state {
+            OTState::Synchronized => (
+                OTState::AwaitingAck(operation.clone()),
+                Some(Envelope::Op {
+                    edit: Edit::Text {
+                        revision: self.revision,
+                        operation,
+                    },
+                }),
+            ),
+            OTState::AwaitingAck(outstanding) => (
+                OTState::AwaitingWithBuffer(outstanding.clone(), operation),
+                None,
+            ),
+            OTState::AwaitingWithBuffer(outstanding, buffer) => {
+                let buffer = TextOperation::compose(buffer, &operation)?;
+                (
+                    OTState::AwaitingWithBuffer(outstanding.clone(), buffer),
+                    None,
+                )
+            }
+        };
+        self.text = text;
+        self.state = state;
+        Ok(message)
+    }
+
+    /// Applies an operation from another client, returning it transformed
+    /// past any local edits the server hasn't seen yet.
+    pub fn apply_server(
+        &mut self,
+        revision: usize,
+        operation: TextOperation,
+    ) -> Result<TextOperation, CollaboriError> {
+        if revision != self.revision {
+            return Err(CollaboriError::UnknownRevision {
+                revision,
+                current: self.revision,
+            });
+        }
+        let (state, operation) = match &self.state {
+            OTState::Synchronized => (OTState::Synchronized, operation),
+            OTState::AwaitingAck(outstanding) => {
+                let (outstanding, operation) = TextOperation::transform(outstanding, &operation)?;
+                (OTState::AwaitingAck(outstanding), operation)
+            }
+            OTState::AwaitingWithBuffer(outstanding, buffer) => {
+                let (outstanding, operation) = TextOperation::transform(outstanding, &operation)?;
+                let (buffer, operation) = TextOperation::transform(buffer, &operation)?;
+                (OTState::AwaitingWithBuffer(outstanding, buffer), operation)
+            }
+        };
+        self.text = operation.apply(&self.text)?;
+        self.state = state;
+        self.revision += 1;
+        Ok(operation)
+    }
+
+    /// Handles the ack for the outstanding operation, returning the buffered
+    /// edits to send next, if any.
+    pub fn server_ack(&mut self) -> Result<Option<Envelope>, CollaboriError> {
+        let (state, message) = match std::mem::replace(&mut self.state, OTState::Synchronized) {
+            OTState::Synchronized => return Err(CollaboriError::UnexpectedAck),
+            OTState::AwaitingAck(_) => (OTState::Synchronized, None),
+            OTState::AwaitingWithBuffer(_, buffer) => (
+                OTState::AwaitingAck(buffer.clone()),
+                Some(Envelope::Op {
+                    edit: Edit::Text {
+                        revision: self.revision + 1,
+                        operation: buffer,
+                    },
+                }),
+            ),
+        };
+        self.state = state;
+        self.revision += 1;
+        Ok(message)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::auth::{Role, StaticAuthenticator};
+    use crate::config::ServerConfig;
+    use crate::data::ElementId;
+    use crate::ot::OTDocument;
     use crate::sync::SyncManager;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
     use tokio::time::Duration;
 
     #[tokio::test]
     async fn test_sync_client() {
-        let addr = "127.0.0.1:9002";
-
-        // Initialize SyncManager
+        // Initialize SyncManager on a free port
         let sync_manager = SyncManager::new();
-        let mut shutdown_handle = sync_manager.start_server(addr).await;
+        let mut server = sync_manager.start_server("127.0.0.1:0").await.unwrap();
+        let addr = &server.local_addr().to_string();
 
         // Give the server a moment to start
         tokio::time::sleep(Duration::from_millis(100)).await;
 
         // Connect the first client
-        let client1 = SyncClient::connect(addr).await;
+        let client1 = SyncClient::connect(addr).await.unwrap();
 
         // Send an operation from client1
         let op1 = Operation::Insert {
             index: 0,
             value: 'a',
-            id: "1".into(),
+            id: ElementId::new(1, 1),
+            origin: None,
         };
-        client1.send_operation(op1.clone()).await;
+        client1.send_operation(op1.clone()).await.unwrap();
 
         // Give the server time to process the operation
         tokio::time::sleep(Duration::from_millis(100)).await;
 
-        // Connect the second client
-        let mut client2 = SyncClient::connect(addr).await; // Declare client2 as mutable
+        // Connect the second client, which catches up on op1 through the
+        // snapshot. It speaks the binary codec while client1 speaks JSON
+        let mut client2 = SyncClient::connect_with_codec(addr, Codec::Binary)
+            .await
+            .unwrap();
+        assert_eq!(client2.snapshot.text(), "a");
 
         // Send another operation from client1
         let op2 = Operation::Insert {
             index: 1,
             value: 'b',
-            id: "2".into(),
+            id: ElementId::new(2, 1),
+            origin: None,
         };
-        client1.send_operation(op2.clone()).await;
+        client1.send_operation(op2.clone()).await.unwrap();
+
+        // client1 gets an ack rather than its own operation back
+        let mut acked = client1.acked.clone();
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            acked.wait_for(|id| *id == Some(ElementId::new(2, 1))),
+        )
+        .await
+        .expect("client1 did not receive an ack")
+        .unwrap();
 
         // Attempt to receive the operation on client2
         if let Some(received_op) = client2.receiver.recv().await {
@@ -136,16 +751,388 @@ mod tests {
             panic!("Did not receive the expected operation on client2");
         }
 
+        // A delete is acked by its stamp, not the id of the deleted element
+        let delete = Operation::Delete {
+            index: 0,
+            id: ElementId::new(1, 1),
+            stamp: ElementId::new(2, 2),
+        };
+        client1.send_operation(delete.clone()).await.unwrap();
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            acked.wait_for(|id| *id == Some(ElementId::new(2, 2))),
+        )
+        .await
+        .expect("client1 did not receive an ack")
+        .unwrap();
+        assert_eq!(client2.receiver.recv().await, Some(delete));
+
         // Shutdown the server
         sync_manager.shutdown().await;
 
         // Wait for the server to confirm shutdown
         let shutdown_confirmation =
-            tokio::time::timeout(Duration::from_secs(5), shutdown_handle.recv()).await;
+            tokio::time::timeout(Duration::from_secs(5), server.stopped()).await;
         assert!(
             shutdown_confirmation.is_ok(),
             "Server did not shut down in time"
         );
         println!("Test completed successfully.");
     }
+
+    /// Forwards connections from `listen` to `target` until aborted, which
+    /// drops every forwarded connection
+    async fn proxy(listen: SocketAddr, target: SocketAddr) -> (SocketAddr, JoinHandle<()>) {
+        let listener = TcpListener::bind(listen).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let task = tokio::spawn(async move {
+            let mut connections = tokio::task::JoinSet::new();
+            while let Ok((mut inbound, _)) = listener.accept().await {
+                connections.spawn(async move {
+                    let mut outbound = TcpStream::connect(target).await.unwrap();
+                    let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+                });
+            }
+        });
+        (addr, task)
+    }
+
+    async fn next_event(client: &mut SyncClient) -> ConnectionEvent {
+        tokio::time::timeout(Duration::from_secs(5), client.events.recv())
+            .await
+            .expect("No connection event")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_sync_client_reconnects() {
+        let sync_manager = SyncManager::new();
+        let mut server = sync_manager.start_server("127.0.0.1:0").await.unwrap();
+        let (proxy_addr, mut link) = proxy(([127, 0, 0, 1], 0).into(), server.local_addr()).await;
+
+        let config = ClientConfig {
+            reconnect: ReconnectPolicy {
+                initial_delay: Duration::from_millis(50),
+                max_delay: Duration::from_millis(200),
+                max_attempts: None,
+            },
+            ..ClientConfig::default()
+        };
+        let mut client1 = SyncClient::connect_with_config(&proxy_addr.to_string(), config)
+            .await
+            .unwrap();
+        let mut client2 = SyncClient::connect(&server.local_addr().to_string())
+            .await
+            .unwrap();
+        assert_eq!(
+            next_event(&mut client1).await,
+            ConnectionEvent::Connected { version: 1 }
+        );
+
+        let mut local = client1.snapshot.clone();
+        local.replica_id = 1;
+        client1.send_operation(local.insert(0, 'a')).await.unwrap();
+        let first = client2.receiver.recv().await.unwrap();
+        assert_eq!(first.last_id().replica, 1);
+
+        // Drop the connection, then edit on both sides while it is down
+        link.abort();
+        let _ = link.await;
+        assert!(matches!(
+            next_event(&mut client1).await,
+            ConnectionEvent::Disconnected { .. }
+        ));
+        let offline = local.insert(1, 'b');
+        client1.send_operation(offline.clone()).await.unwrap();
+        let mut remote = client2.snapshot.clone();
+        remote.replica_id = 2;
+        remote.apply(&first);
+        let missed = remote.insert(0, 'x');
+        client2.send_operation(missed.clone()).await.unwrap();
+        let mut acked = client2.acked.clone();
+        acked
+            .wait_for(|id| *id == Some(missed.last_id()))
+            .await
+            .unwrap();
+
+        (_, link) = proxy(proxy_addr, server.local_addr()).await;
+        let resynced = loop {
+            match next_event(&mut client1).await {
+                ConnectionEvent::Reconnecting { attempt, delay } => {
+                    assert!(delay <= Duration::from_millis(200), "attempt {}", attempt)
+                }
+                ConnectionEvent::Connected { .. } | ConnectionEvent::Disconnected { .. } => {}
+                event => break event,
+            }
+        };
+        assert_eq!(
+            resynced,
+            ConnectionEvent::Resynced {
+                missed: 1,
+                resent: 1
+            }
+        );
+
+        // client1 catches up on the remote edit and its queued edit goes out
+        local.apply(&client1.receiver.recv().await.unwrap());
+        remote.apply(&client2.receiver.recv().await.unwrap());
+        assert_eq!(local.text(), "xab");
+        assert_eq!(remote.text(), "xab");
+        let mut acked = client1.acked.clone();
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            acked.wait_for(|id| *id == Some(offline.last_id())),
+        )
+        .await
+        .expect("client1 did not receive an ack")
+        .unwrap();
+
+        link.abort();
+        sync_manager.shutdown().await;
+        server.stopped().await;
+    }
+
+    #[tokio::test]
+    async fn test_sync_client_shares_awareness() {
+        let sync_manager = SyncManager::new();
+        let mut server = sync_manager.start_server("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().to_string();
+        let client1 = SyncClient::connect(&addr).await.unwrap();
+        let client2 = SyncClient::connect(&addr).await.unwrap();
+
+        let state = Awareness {
+            user_id: "alice".to_string(),
+            name: "Alice".to_string(),
+            color: "#2196f3".to_string(),
+            selection: Some(crate::data::Selection { anchor: 0, head: 3 }),
+        };
+        client1.set_awareness(Some(state.clone()));
+        let mut peers = client2.peers.clone();
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            peers.wait_for(|peers| peers.values().eq([&state])),
+        )
+        .await
+        .expect("client2 did not see client1")
+        .unwrap();
+        assert!(client1.peers.borrow().is_empty());
+
+        drop(client1);
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            peers.wait_for(|peers| peers.is_empty()),
+        )
+        .await
+        .expect("client1 did not leave")
+        .unwrap();
+
+        server.shutdown();
+        server.stopped().await;
+    }
+
+    #[tokio::test]
+    async fn test_sync_client_heartbeats() {
+        let heartbeats = ClientConfig {
+            ping_interval: Some(Duration::from_millis(50)),
+            max_missed_pings: 2,
+            ..ClientConfig::default()
+        };
+
+        // A live server answers every ping
+        let sync_manager = SyncManager::with_config(ServerConfig {
+            ping_interval: Some(Duration::from_millis(50)),
+            max_missed_pings: 2,
+            ..ServerConfig::default()
+        });
+        let mut server = sync_manager.start_server("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().to_string();
+        let mut client = SyncClient::connect_with_config(&addr, heartbeats.clone())
+            .await
+            .unwrap();
+        let mut latency = client.latency.clone();
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            latency.wait_for(|latency| latency.is_some()),
+        )
+        .await
+        .expect("No round trip was measured")
+        .unwrap();
+        assert_eq!(
+            next_event(&mut client).await,
+            ConnectionEvent::Connected { version: 1 }
+        );
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(client.events.try_recv().is_err());
+        server.shutdown();
+        server.stopped().await;
+
+        // A server that stops answering is given up on
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let silent = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws_stream = tokio_tungstenite::accept_async(stream).await.unwrap();
+            ws_stream.next().await;
+            for envelope in [
+                Envelope::hello(),
+                Envelope::Snapshot {
+                    document: "default".to_string(),
+                    state: DocumentSnapshot::Crdt(Box::default()),
+                },
+            ] {
+                ws_stream.send(Codec::Json.encode(&envelope)).await.unwrap();
+            }
+            std::future::pending::<()>().await;
+        });
+        let mut client = SyncClient::connect_with_config(&addr, heartbeats)
+            .await
+            .unwrap();
+        next_event(&mut client).await;
+        match next_event(&mut client).await {
+            ConnectionEvent::Disconnected { reason } => assert!(reason.contains("heartbeats")),
+            other => panic!("Expected a disconnection, got {:?}", other),
+        }
+        assert!(client.latency.borrow().is_none());
+        silent.abort();
+    }
+
+    #[tokio::test]
+    async fn test_sync_client_connects_despite_allowed_origins() {
+        // Origin checks keep out browsers on other sites, not native clients
+        let sync_manager = SyncManager::with_config(ServerConfig {
+            allowed_origins: vec!["https://docs.example.com".to_string()],
+            ..ServerConfig::default()
+        });
+        let mut server = sync_manager.start_server("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().to_string();
+        let mut client = SyncClient::connect(&addr).await.unwrap();
+        assert_eq!(
+            next_event(&mut client).await,
+            ConnectionEvent::Connected { version: 1 }
+        );
+        server.shutdown();
+        server.stopped().await;
+    }
+
+    #[tokio::test]
+    async fn test_connection_failures_are_errors() {
+        // Nothing listens on a port that was just released
+        let free = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        assert!(matches!(
+            SyncClient::connect(&free.to_string()).await,
+            Err(CollaboriError::WebSocketError(_))
+        ));
+        assert!(matches!(
+            SyncClient::connect("not an address").await,
+            Err(CollaboriError::InvalidAddress(_))
+        ));
+
+        let authenticator = StaticAuthenticator::new()
+            .with_token("secret", "alice")
+            .with_default_role(Role::Editor);
+        let sync_manager = SyncManager::new().with_authenticator(Arc::new(authenticator));
+        let mut server = sync_manager.start_server("127.0.0.1:0").await.unwrap();
+        let taken = server.local_addr().to_string();
+        assert!(matches!(
+            SyncClient::connect(&taken).await,
+            Err(CollaboriError::Unauthorized(_))
+        ));
+        let config = ClientConfig {
+            token: Some("secret".to_string()),
+            ..ClientConfig::default()
+        };
+        assert!(SyncClient::connect_with_config(&taken, config)
+            .await
+            .is_ok());
+
+        assert!(matches!(
+            SyncManager::new().start_server(&taken).await,
+            Err(CollaboriError::BindError { .. })
+        ));
+
+        server.shutdown();
+        server.stopped().await;
+    }
+
+    /// Hands a client's message to the server, returning the transformed
+    /// operation with the revision it was applied on
+    fn deliver(server: &mut OTDocument, message: Option<Envelope>) -> (usize, TextOperation) {
+        match message {
+            Some(Envelope::Op {
+                edit:
+                    Edit::Text {
+                        revision,
+                        operation,
+                    },
+            }) => {
+                let operation = server.receive(revision, operation).unwrap();
+                (server.revision() - 1, operation)
+            }
+            other => panic!("Expected an operation, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_ot_client_buffers_while_awaiting_ack() {
+        let mut server = OTDocument::new("abc");
+        let mut alice = OTClient::new(0, "abc");
+        let mut bob = OTClient::new(0, "abc");
+
+        // Alice sends one edit and buffers two more while it is in flight
+        let sent = alice
+            .apply_local(TextOperation::new().retain(3).insert("d"))
+            .unwrap();
+        assert!(alice
+            .apply_local(TextOperation::new().retain(4).insert("e"))
+            .unwrap()
+            .is_none());
+        assert!(alice
+            .apply_local(TextOperation::new().delete(1).retain(4))
+            .unwrap()
+            .is_none());
+        assert!(matches!(alice.state(), OTState::AwaitingWithBuffer(..)));
+        assert_eq!(alice.text(), "bcde");
+
+        // Bob's concurrent edit reaches the server first
+        let bob_sent = bob
+            .apply_local(TextOperation::new().retain(1).insert("X").retain(2))
+            .unwrap();
+        let (revision, bob_op) = deliver(&mut server, bob_sent);
+        assert!(bob.server_ack().unwrap().is_none());
+        assert_eq!(bob.state(), &OTState::Synchronized);
+
+        let (revision, alice_op) = {
+            let applied = alice.apply_server(revision, bob_op).unwrap();
+            assert_eq!(alice.text(), "Xbcde");
+            assert_eq!(applied.base_len(), 4);
+            deliver(&mut server, sent)
+        };
+        bob.apply_server(revision, alice_op).unwrap();
+
+        // The ack releases the buffer, composed into a single operation
+        let buffered = alice.server_ack().unwrap();
+        let (revision, alice_op) = deliver(&mut server, buffered);
+        bob.apply_server(revision, alice_op).unwrap();
+        alice.server_ack().unwrap();
+
+        assert_eq!(server.text(), "Xbcde");
+        assert_eq!(alice.text(), server.text());
+        assert_eq!(bob.text(), server.text());
+        assert_eq!(alice.revision(), 3);
+        assert_eq!(bob.revision(), 3);
+        assert_eq!(alice.state(), &OTState::Synchronized);
+
+        assert!(matches!(
+            alice.server_ack(),
+            Err(CollaboriError::UnexpectedAck)
+        ));
+        assert!(matches!(
+            bob.apply_server(1, TextOperation::new().retain(5)),
+            Err(CollaboriError::UnknownRevision { .. })
+        ));
+    }
 }
@@ -0,0 +1,565 @@
+use crate::data::{Awareness, ElementId, Operation, Selection, VersionVector};
+use crate::errors::CollaboriError;
+use crate::ot::{Component, TextOperation};
+
+/// Compact binary encoding, used for binary WebSocket frames and stored
+/// documents.
+///
+/// Integers are LEB128 varints, strings and lists are length-prefixed, and
+/// lists of ids are stored as runs of consecutive counters from one replica.
+pub trait Encode {
+    fn encode(&self, out: &mut Vec<u8>);
+}
+
+pub trait Decode: Sized {
+    /// Reads a value from the front of `input`, advancing it
+    fn decode(input: &mut &[u8]) -> Result<Self, CollaboriError>;
+}
+
+pub fn to_bytes<T: Encode + ?Sized>(value: &T) -> Vec<u8> {
+    let mut out = Vec::new();
+    value.encode(&mut out);
+    out
+}
+
+/// Decodes a value that must span all of `bytes`
+pub fn from_bytes<T: Decode>(mut bytes: &[u8]) -> Result<T, CollaboriError> {
+    let value = T::decode(&mut bytes)?;
+    if !bytes.is_empty() {
+        return Err(malformed("trailing bytes"));
+    }
+    Ok(value)
+}
+
+pub(crate) fn malformed(reason: &str) -> CollaboriError {
+    CollaboriError::DecodeError(reason.to_string())
+}
+
+/// Reads a one-byte tag
+pub(crate) fn decode_tag(input: &mut &[u8]) -> Result<u8, CollaboriError> {
+    let (&tag, rest) = input.split_first().ok_or_else(|| malformed("truncated"))?;
+    *input = rest;
+    Ok(tag)
+}
+
+impl Encode for u64 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let mut value = *self;
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+}
+
+impl Decode for u64 {
+    fn decode(input: &mut &[u8]) -> Result<Self, CollaboriError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = decode_tag(input)?;
+            if shift == 63 && byte > 1 {
+                return Err(malformed("varint overflows u64"));
+            }
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(malformed("varint overflows u64"))
+    }
+}
+
+impl Encode for usize {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as u64).encode(out);
+    }
+}
+
+impl Decode for usize {
+    fn decode(input: &mut &[u8]) -> Result<Self, CollaboriError> {
+        usize::try_from(u64::decode(input)?).map_err(|_| malformed("length overflows usize"))
+    }
+}
+
+impl Encode for u32 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        u64::from(*self).encode(out);
+    }
+}
+
+impl Decode for u32 {
+    fn decode(input: &mut &[u8]) -> Result<Self, CollaboriError> {
+        u32::try_from(u64::decode(input)?).map_err(|_| malformed("value overflows u32"))
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+}
+
+impl Decode for bool {
+    fn decode(input: &mut &[u8]) -> Result<Self, CollaboriError> {
+        match decode_tag(input)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(malformed("invalid bool")),
+        }
+    }
+}
+
+impl Encode for char {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as u32).encode(out);
+    }
+}
+
+impl Decode for char {
+    fn decode(input: &mut &[u8]) -> Result<Self, CollaboriError> {
+        char::from_u32(u32::decode(input)?).ok_or_else(|| malformed("invalid char"))
+    }
+}
+
+impl Encode for str {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.len().encode(out);
+        out.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.as_str().encode(out);
+    }
+}
+
+impl Decode for String {
+    fn decode(input: &mut &[u8]) -> Result<Self, CollaboriError> {
+        let len = usize::decode(input)?;
+        if len > input.len() {
+            return Err(malformed("truncated"));
+        }
+        let (bytes, rest) = input.split_at(len);
+        *input = rest;
+        String::from_utf8(bytes.to_vec()).map_err(|_| malformed("invalid UTF-8"))
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Some(value) => {
+                out.push(1);
+                value.encode(out);
+            }
+            None => out.push(0),
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(input: &mut &[u8]) -> Result<Self, CollaboriError> {
+        match decode_tag(input)? {
+            0 => Ok(None),
+            1 => Ok(Some(T::decode(input)?)),
+            _ => Err(malformed("invalid option")),
+        }
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.len().encode(out);
+        for item in self {
+            item.encode(out);
+        }
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(input: &mut &[u8]) -> Result<Self, CollaboriError> {
+        let len = usize::decode(input)?;
+        // Every item takes at least a byte, which bounds what a bogus length
+        // can make us allocate
+        let mut items = Vec::with_capacity(len.min(input.len()));
+        for _ in 0..len {
+            items.push(T::decode(input)?);
+        }
+        Ok(items)
+    }
+}
+
+impl<A: Encode, B: Encode> Encode for (A, B) {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.0.encode(out);
+        self.1.encode(out);
+    }
+}
+
+impl<A: Decode, B: Decode> Decode for (A, B) {
+    fn decode(input: &mut &[u8]) -> Result<Self, CollaboriError> {
+        Ok((A::decode(input)?, B::decode(input)?))
+    }
+}
+
+impl Encode for ElementId {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.counter.encode(out);
+        self.replica.encode(out);
+    }
+}
+
+impl Decode for ElementId {
+    fn decode(input: &mut &[u8]) -> Result<Self, CollaboriError> {
+        let counter = u64::decode(input)?;
+        let replica = u64::decode(input)?;
+        Ok(ElementId::new(replica, counter))
+    }
+}
+
+/// Writes ids as `(first id, length)` runs of consecutive counters
+pub(crate) fn encode_id_runs(ids: &[ElementId], out: &mut Vec<u8>) {
+    let mut runs: Vec<(ElementId, usize)> = Vec::new();
+    for id in ids {
+        match runs.last_mut() {
+            Some((first, len))
+                if first.replica == id.replica && first.counter + *len as u64 == id.counter =>
+            {
+                *len += 1
+            }
+            _ => runs.push((*id, 1)),
+        }
+    }
+    runs.encode(out);
+}
+
+/// The most ids a list may expand to, since runs are far smaller than the
+/// ids they stand for
+const MAX_IDS: usize = 1 << 24;
+
+/// The most ids a list may expand to per byte of input left when it starts,
+/// enough for a document typed in one go to be deleted at once without
+/// letting a tiny frame expand into millions of ids
+const MAX_IDS_PER_BYTE: usize = 1 << 12;
+
+/// Reads a list of `len` ids written by [`encode_id_runs`]
+pub(crate) fn decode_id_runs(
+    input: &mut &[u8],
+    len: usize,
+) -> Result<Vec<ElementId>, CollaboriError> {
+    if len > MAX_IDS || len > input.len().saturating_mul(MAX_IDS_PER_BYTE) {
+        return Err(malformed("too many ids"));
+    }
+    let mut ids = Vec::with_capacity(len);
+    for (first, run) in Vec::<(ElementId, usize)>::decode(input)? {
+        if run > len - ids.len() {
+            return Err(malformed("more ids than the operation covers"));
+        }
+        if run > 0 && first.counter.checked_add(run as u64 - 1).is_none() {
+            return Err(malformed("id counter overflow"));
+        }
+        ids.extend((0..run as u64).map(|i| ElementId::new(first.replica, first.counter + i)));
+    }
+    if ids.len() != len {
+        return Err(malformed("fewer ids than the operation covers"));
+    }
+    Ok(ids)
+}
+
+impl Encode for Operation {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Operation::Insert {
+                index,
+                value,
+                id,
+                origin,
+            } => {
+                out.push(0);
+                index.encode(out);
+                value.encode(out);
+                id.encode(out);
+                origin.encode(out);
+            }
+            Operation::Delete { index, id, stamp } => {
+                out.push(1);
+                index.encode(out);
+                id.encode(out);
+                stamp.encode(out);
+            }
+            Operation::InsertText {
+                index,
+                text,
+                id,
+                origin,
+            } => {
+                out.push(2);
+                index.encode(out);
+                text.encode(out);
+                id.encode(out);
+                origin.encode(out);
+            }
+            Operation::DeleteRange {
+                index,
+                len,
+                ids,
+                stamp,
+            } => {
+                out.push(3);
+                index.encode(out);
+                len.encode(out);
+                encode_id_runs(ids, out);
+                stamp.encode(out);
+            }
+            Operation::Noop { id } => {
+                out.push(4);
+                id.encode(out);
+            }
+        }
+    }
+}
+
+impl Decode for Operation {
+    fn decode(input: &mut &[u8]) -> Result<Self, CollaboriError> {
+        Ok(match decode_tag(input)? {
+            0 => Operation::Insert {
+                index: usize::decode(input)?,
+                value: char::decode(input)?,
+                id: ElementId::decode(input)?,
+                origin: Option::decode(input)?,
+            },
+            1 => Operation::Delete {
+                index: usize::decode(input)?,
+                id: ElementId::decode(input)?,
+                stamp: ElementId::decode(input)?,
+            },
+            2 => Operation::InsertText {
+                index: usize::decode(input)?,
+                text: String::decode(input)?,
+                id: ElementId::decode(input)?,
+                origin: Option::decode(input)?,
+            },
+            3 => {
+                let index = usize::decode(input)?;
+                let len = usize::decode(input)?;
+                Operation::DeleteRange {
+                    index,
+                    len,
+                    ids: decode_id_runs(input, len)?,
+                    stamp: ElementId::decode(input)?,
+                }
+            }
+            4 => Operation::Noop {
+                id: ElementId::decode(input)?,
+            },
+            _ => return Err(malformed("unknown operation")),
+        })
+    }
+}
+
+impl Encode for VersionVector {
+    fn encode(&self, out: &mut Vec<u8>) {
+        Vec::<(u64, u64)>::from(self.clone()).encode(out);
+    }
+}
+
+impl Decode for VersionVector {
+    fn decode(input: &mut &[u8]) -> Result<Self, CollaboriError> {
+        Ok(Vec::<(u64, u64)>::decode(input)?.into())
+    }
+}
+
+impl Encode for Selection {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.anchor.encode(out);
+        self.head.encode(out);
+    }
+}
+
+impl Decode for Selection {
+    fn decode(input: &mut &[u8]) -> Result<Self, CollaboriError> {
+        Ok(Selection {
+            anchor: usize::decode(input)?,
+            head: usize::decode(input)?,
+        })
+    }
+}
+
+impl Encode for Awareness {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.user_id.encode(out);
+        self.name.encode(out);
+        self.color.encode(out);
+        self.selection.encode(out);
+    }
+}
+
+impl Decode for Awareness {
+    fn decode(input: &mut &[u8]) -> Result<Self, CollaboriError> {
+        Ok(Awareness {
+            user_id: String::decode(input)?,
+            name: String::decode(input)?,
+            color: String::decode(input)?,
+            selection: Option::decode(input)?,
+        })
+    }
+}
+
+impl Encode for TextOperation {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.components().len().encode(out);
+        for component in self.components() {
+            match component {
+                Component::Retain(n) => {
+                    out.push(0);
+                    n.encode(out);
+                }
+                Component::Insert(text) => {
+                    out.push(1);
+                    text.encode(out);
+                }
+                Component::Delete(n) => {
+                    out.push(2);
+                    n.encode(out);
+                }
+            }
+        }
+    }
+}
+
+impl Decode for TextOperation {
+    fn decode(input: &mut &[u8]) -> Result<Self, CollaboriError> {
+        let mut operation = TextOperation::new();
+        for _ in 0..usize::decode(input)? {
+            operation = match decode_tag(input)? {
+                0 => operation.try_retain(usize::decode(input)?),
+                1 => operation.try_insert(&String::decode(input)?),
+                2 => operation.try_delete(usize::decode(input)?),
+                _ => return Err(malformed("unknown text operation component")),
+            }
+            .map_err(|_| malformed("text operation length overflow"))?;
+        }
+        Ok(operation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_varints_round_trip() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let bytes = to_bytes(&value);
+            assert_eq!(from_bytes::<u64>(&bytes).unwrap(), value);
+        }
+        assert_eq!(to_bytes(&127u64).len(), 1);
+        assert_eq!(to_bytes(&128u64).len(), 2);
+        assert!(from_bytes::<u64>(&[0xff; 11]).is_err());
+        assert!(from_bytes::<u64>(&[0x80]).is_err());
+        assert!(from_bytes::<u64>(&[1, 2]).is_err());
+    }
+
+    #[test]
+    fn test_operations_round_trip() {
+        let replica = u64::MAX - 7;
+        let ops = vec![
+            Operation::Insert {
+                index: 3,
+                value: 'é',
+                id: ElementId::new(replica, 12),
+                origin: Some(ElementId::new(2, 1)),
+            },
+            Operation::Delete {
+                index: 0,
+                id: ElementId::new(2, 1),
+                stamp: ElementId::new(replica, 13),
+            },
+            Operation::InsertText {
+                index: 0,
+                text: "hello ✓".to_string(),
+                id: ElementId::new(replica, 14),
+                origin: None,
+            },
+            Operation::DeleteRange {
+                index: 1,
+                len: 5,
+                ids: vec![
+                    ElementId::new(replica, 15),
+                    ElementId::new(replica, 16),
+                    ElementId::new(replica, 17),
+                    ElementId::new(2, 4),
+                    ElementId::new(replica, 18),
+                ],
+                stamp: ElementId::new(replica, 30),
+            },
+            Operation::Noop {
+                id: ElementId::new(replica, 31),
+            },
+        ];
+        for op in &ops {
+            assert_eq!(&from_bytes::<Operation>(&to_bytes(op)).unwrap(), op);
+        }
+
+        // A range of consecutive ids costs one run whatever its length
+        let range = |len: u64| Operation::DeleteRange {
+            index: 0,
+            len: len as usize,
+            ids: (1..=len).map(|c| ElementId::new(replica, c)).collect(),
+            stamp: ElementId::new(replica, len + 1),
+        };
+        assert!(to_bytes(&range(1000)).len() < to_bytes(&range(2)).len() + 8);
+    }
+
+    #[test]
+    fn test_id_runs_are_bounded() {
+        let delete_range = |len: usize, runs: Vec<(ElementId, usize)>| {
+            let mut bytes = vec![3];
+            0usize.encode(&mut bytes);
+            len.encode(&mut bytes);
+            runs.encode(&mut bytes);
+            ElementId::new(1, 1).encode(&mut bytes);
+            from_bytes::<Operation>(&bytes)
+        };
+        let id = ElementId::new(1, 2);
+        assert!(delete_range(3, vec![(id, 3)]).is_ok());
+
+        // A few bytes can't stand for millions of ids
+        assert!(delete_range(MAX_IDS, vec![(id, MAX_IDS)]).is_err());
+        assert!(delete_range(1 << 20, vec![(id, 1 << 20)]).is_err());
+        // Runs must cover exactly the operation's length
+        assert!(delete_range(2, vec![(id, 3)]).is_err());
+        assert!(delete_range(4, vec![(id, 3)]).is_err());
+        // Counters can't wrap around
+        assert!(delete_range(2, vec![(ElementId::new(1, u64::MAX), 2)]).is_err());
+        assert!(delete_range(1, vec![(ElementId::new(1, u64::MAX), 1)]).is_ok());
+    }
+
+    #[test]
+    fn test_text_operations_round_trip() {
+        let operation = TextOperation::new()
+            .retain(3)
+            .insert("ab")
+            .delete(2)
+            .retain(1);
+        let bytes = to_bytes(&operation);
+        assert_eq!(from_bytes::<TextOperation>(&bytes).unwrap(), operation);
+        assert!(from_bytes::<TextOperation>(&bytes[..bytes.len() - 1]).is_err());
+
+        // Lengths that overflow are rejected
+        for tag in [0u8, 2] {
+            let mut bytes = Vec::new();
+            2usize.encode(&mut bytes);
+            for n in [usize::MAX, 1] {
+                bytes.push(tag);
+                n.encode(&mut bytes);
+            }
+            assert!(matches!(
+                from_bytes::<TextOperation>(&bytes),
+                Err(CollaboriError::DecodeError(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use crate::auth::Authenticator;
+use crate::errors::CollaboriError;
+use crate::protocol::DEFAULT_MAX_MISSED_PINGS;
+use crate::store::{DocumentStore, FileStore};
+use crate::sync::{
+    LagPolicy, SyncMode, DEFAULT_CONNECTION_CAPACITY, DEFAULT_HANDSHAKE_TIMEOUT,
+    DEFAULT_PING_INTERVAL, DEFAULT_PRESENCE_TIMEOUT, DEFAULT_ROOM_CAPACITY,
+    DEFAULT_SHUTDOWN_TIMEOUT, DEFAULT_SNAPSHOT_INTERVAL,
+};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The address [`ServerConfig::address`] defaults to
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:8080";
+
+/// Settings of a [`crate::sync::SyncManager`], passed to
+/// [`crate::sync::SyncManager::with_config`].
+///
+/// Everything but the authenticator can also be read from a TOML file, with
+/// durations in seconds and the store given as a [`FileStore`] directory,
+/// which is only created once the server starts. Zero is refused for the
+/// durations a server needs, and turns the optional ones off, like leaving
+/// them out:
+///
+/// ```toml
+/// address = "0.0.0.0:9000"
+/// mode = "crdt"
+/// max_connections = 500
+/// idle_timeout = 300
+/// ping_interval = 15
+/// max_missed_pings = 2
+/// allowed_origins = ["https://docs.example.com"]
+///
+/// [store]
+/// path = "/var/lib/collabori"
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Where [`crate::sync::SyncManager::start`] listens
+    pub address: String,
+    pub mode: SyncMode,
+    /// Operations a room logs before snapshotting its document
+    pub snapshot_interval: usize,
+    /// How long a client's awareness state lasts without being refreshed
+    #[serde(deserialize_with = "seconds")]
+    pub presence_timeout: Duration,
+    /// Updates a room buffers for connections that haven't sent them yet
+    pub room_capacity: usize,
+    /// Messages a connection queues while its socket is busy
+    pub connection_capacity: usize,
+    /// What happens to connections that fall more than `room_capacity` behind
+    pub lag_policy: LagPolicy,
+    /// Largest message, in bytes, a client may send
+    pub max_message_size: Option<usize>,
+    /// Connections served at once; more are answered with 503
+    pub max_connections: Option<usize>,
+    /// How long a client may stay silent before it is disconnected, `None`
+    /// or zero to never
+    #[serde(deserialize_with = "optional_seconds")]
+    pub idle_timeout: Option<Duration>,
+    /// How long a connection may take to upgrade and send its hello before
+    /// it is dropped, `None` or zero to wait forever
+    #[serde(deserialize_with = "optional_seconds")]
+    pub handshake_timeout: Option<Duration>,
+    /// How often the server pings its clients, `None` or zero to never
+    #[serde(deserialize_with = "optional_seconds")]
+    pub ping_interval: Option<Duration>,
+    /// Pings a client may leave unanswered in a row before it is disconnected
+    pub max_missed_pings: u32,
+    /// How long shutting down waits for connections to close before dropping
+    /// them
+    #[serde(deserialize_with = "seconds")]
+    pub shutdown_timeout: Duration,
+    /// Origins browsers may connect from, any when empty. Handshakes from
+    /// other origins are answered with 403. Handshakes without an origin
+    /// come from native clients such as [`crate::client::SyncClient`], since
+    /// browsers always send one, and are let through.
+    pub allowed_origins: Vec<String>,
+    /// Persists documents, which requires [`SyncMode::Crdt`]
+    #[serde(deserialize_with = "file_store")]
+    pub store: Option<Arc<dyn DocumentStore>>,
+    /// Authenticates connections and decides their roles; anyone may edit
+    /// anything without one
+    #[serde(skip)]
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: DEFAULT_ADDRESS.to_string(),
+            mode: SyncMode::default(),
+            snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+            presence_timeout: DEFAULT_PRESENCE_TIMEOUT,
+            room_capacity: DEFAULT_ROOM_CAPACITY,
+            connection_capacity: DEFAULT_CONNECTION_CAPACITY,
+            lag_policy: LagPolicy::default(),
+            max_message_size: None,
+            max_connections: None,
+            idle_timeout: None,
+            handshake_timeout: Some(DEFAULT_HANDSHAKE_TIMEOUT),
+            ping_interval: Some(DEFAULT_PING_INTERVAL),
+            max_missed_pings: DEFAULT_MAX_MISSED_PINGS,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            allowed_origins: Vec::new(),
+            store: None,
+            authenticator: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Reads a configuration from a TOML document
+    pub fn from_toml(toml: &str) -> Result<Self, CollaboriError> {
+        let config: ServerConfig = toml::from_str(toml)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reads a configuration from a TOML file
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, CollaboriError> {
+        Self::from_toml(&std::fs::read_to_string(path)?)
+    }
+
+    /// Checks that the settings can be served together. Only CRDT documents
+    /// can be stored, so a store in [`SyncMode::Ot`] is refused rather than
+    /// silently losing every edit on restart.
+    pub fn validate(&self) -> Result<(), CollaboriError> {
+        if self.mode == SyncMode::Ot && self.store.is_some() {
+            return Err(CollaboriError::UnsupportedConfig(
+                "documents edited in OT mode can't be stored".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns true if a handshake with the given `Origin` header may proceed
+    pub fn allows_origin(&self, origin: Option<&str>) -> bool {
+        match origin {
+            Some(origin) => {
+                self.allowed_origins.is_empty() || self.allowed_origins.iter().any(|o| o == origin)
+            }
+            None => true,
+        }
+    }
+}
+
+fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    match optional_seconds(deserializer)? {
+        Some(duration) => Ok(duration),
+        None => Err(D::Error::custom("duration must be positive")),
+    }
+}
+
+/// Reads a duration in seconds, zero meaning none
+fn optional_seconds<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    let duration =
+        Duration::try_from_secs_f64(f64::deserialize(deserializer)?).map_err(D::Error::custom)?;
+    Ok((!duration.is_zero()).then_some(duration))
+}
+
+fn file_store<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Arc<dyn DocumentStore>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct StoreConfig {
+        path: PathBuf,
+    }
+    let config = StoreConfig::deserialize(deserializer)?;
+    Ok(Some(Arc::new(FileStore::new(config.path))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_config_from_toml() {
+        let dir = std::env::temp_dir().join(format!("collabori-config-{}", std::process::id()));
+        let config = ServerConfig::from_toml(&format!(
+            r#"
+            address = "0.0.0.0:9000"
+            mode = "crdt"
+            lag_policy = "disconnect"
+            max_connections = 2
+            idle_timeout = 1.5
+            handshake_timeout = 2
+            ping_interval = 15
+            max_missed_pings = 2
+            shutdown_timeout = 5
+            allowed_origins = ["https://docs.example.com"]
+
+            [store]
+            path = {:?}
+            "#,
+            dir
+        ))
+        .unwrap();
+        assert_eq!(config.address, "0.0.0.0:9000");
+        assert_eq!(config.mode, SyncMode::Crdt);
+        assert_eq!(config.lag_policy, LagPolicy::Disconnect);
+        assert_eq!(config.max_connections, Some(2));
+        assert_eq!(config.idle_timeout, Some(Duration::from_millis(1500)));
+        assert_eq!(config.handshake_timeout, Some(Duration::from_secs(2)));
+        assert_eq!(config.ping_interval, Some(Duration::from_secs(15)));
+        assert_eq!(config.max_missed_pings, 2);
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(5));
+        assert!(config.allows_origin(Some("https://docs.example.com")));
+        assert!(!config.allows_origin(Some("https://evil.example.com")));
+        assert!(config.allows_origin(None));
+
+        // The store's directory is only created once a server starts
+        assert!(!dir.is_dir());
+        config.store.unwrap().init().unwrap();
+        assert!(dir.is_dir());
+
+        // Unset fields keep their defaults
+        assert_eq!(config.room_capacity, DEFAULT_ROOM_CAPACITY);
+        assert_eq!(config.presence_timeout, DEFAULT_PRESENCE_TIMEOUT);
+        assert!(ServerConfig::default().allows_origin(None));
+
+        assert!(matches!(
+            ServerConfig::from_toml("max_conections = 2"),
+            Err(CollaboriError::ConfigError(_))
+        ));
+        assert!(matches!(
+            ServerConfig::from_toml("idle_timeout = -1"),
+            Err(CollaboriError::ConfigError(_))
+        ));
+
+        // Zero turns optional durations off and is refused for the others
+        let config = ServerConfig::from_toml("idle_timeout = 0\nping_interval = 0").unwrap();
+        assert_eq!(config.idle_timeout, None);
+        assert_eq!(config.ping_interval, None);
+        assert!(matches!(
+            ServerConfig::from_toml("presence_timeout = 0"),
+            Err(CollaboriError::ConfigError(_))
+        ));
+        let ot_store = format!("mode = \"ot\"\n[store]\npath = {:?}", dir);
+        assert!(matches!(
+            ServerConfig::from_toml(&ot_store),
+            Err(CollaboriError::UnsupportedConfig(_))
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,16 +1,46 @@
-use crate::data::Operation;
-use crate::utils::generate_unique_id;
+use crate::codec::{Decode, Encode};
+use crate::data::{ElementId, Operation, VersionVector};
+use crate::errors::CollaboriError;
+use crate::tree::ElementTree;
+use crate::utils::generate_replica_id;
+use crate::CRDT;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// How far past a replica's clock the ids of a remote operation may reach.
+/// Clocks only leap as far as the sender edited without hearing from this
+/// replica, so longer leaps come from broken or malicious peers that would
+/// otherwise push every clock they reach to its limit.
+const MAX_CLOCK_LEAP: u64 = 1 << 32;
+
+/// The highest counter a remote operation may use, leaving the rest of the
+/// range to the replica's own operations
+const MAX_COUNTER: u64 = u64::MAX / 2;
+
+/// How many operations from one replica may wait for their dependencies
+const MAX_PENDING_PER_REPLICA: usize = 1024;
 
 /// Replicated Growable Array (RGA) CRDT implementation
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RGA {
-    pub elements: Vec<Element>,
+    pub replica_id: u64,
+    clock: u64,
+    pub elements: ElementTree,
+    #[serde(default)]
+    pending: Vec<Operation>, // Remote operations waiting for their causal dependencies
+    #[serde(default)]
+    version: VersionVector, // Every insert id and delete stamp observed so far
+    #[serde(default, with = "stamp_list")]
+    tombstones: HashMap<ElementId, ElementId>, // Deleted element id -> delete stamp
+    #[serde(default)]
+    collected: VersionVector, // Stable frontier at the last garbage collection
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Element {
-    pub id: String,
+    pub id: ElementId,
+    pub origin: Option<ElementId>, // Left neighbour at insertion time, `None` for the head
     pub value: char,
     pub visible: bool,
 }
@@ -22,51 +52,650 @@ impl Default for RGA {
 }
 
 impl RGA {
-    /// Creates a new RGA instance
+    /// Creates a new RGA instance with a random replica id
     pub fn new() -> Self {
+        Self::with_replica_id(generate_replica_id())
+    }
+
+    /// Creates a new RGA instance for the given replica
+    pub fn with_replica_id(replica_id: u64) -> Self {
         RGA {
-            elements: Vec::new(),
+            replica_id,
+            clock: 0,
+            elements: ElementTree::new(),
+            pending: Vec::new(),
+            version: VersionVector::new(),
+            tombstones: HashMap::new(),
+            collected: VersionVector::new(),
         }
     }
 
-    /// Inserts a character at a specified position
+    /// Inserts a character at a specified visible position
     pub fn insert(&mut self, index: usize, value: char) -> Operation {
-        let id = generate_unique_id();
-        let element = Element {
-            id: id.clone(),
+        if index > self.len() {
+            panic!("Index out of bounds");
+        }
+        let origin = index.checked_sub(1).and_then(|i| self.id_at(i));
+        let id = self.next_id();
+        self.integrate(Element {
+            id,
+            origin,
             value,
             visible: true,
-        };
-        self.elements.insert(index, element.clone());
-        Operation::Insert { id, value, index }
+        });
+        Operation::Insert {
+            index,
+            value,
+            id,
+            origin,
+        }
     }
 
-    /// Deletes a character at a specified position
+    /// Deletes the character at a specified visible position
     pub fn delete(&mut self, index: usize) -> Operation {
-        if let Some(element) = self.elements.get_mut(index) {
-            element.visible = false;
-            return Operation::Delete {
-                id: element.id.clone(),
-                index,
-            };
+        if let Some(id) = self.id_at(index) {
+            let stamp = self.next_id();
+            self.mark_deleted(id, stamp);
+            return Operation::Delete { id, index, stamp };
         }
         panic!("Index out of bounds");
     }
 
-    /// Merges another RGA state into this one
+    /// Inserts a string at a specified visible position as one operation.
+    /// Empty text inserts nothing and yields a no-op with a fresh id, since
+    /// a run without characters has no id of its own.
+    pub fn insert_text(&mut self, index: usize, text: &str) -> Operation {
+        if index > self.len() {
+            panic!("Index out of bounds");
+        }
+        if text.is_empty() {
+            let id = self.next_id();
+            self.version.observe(id);
+            return Operation::Noop { id };
+        }
+        let origin = index.checked_sub(1).and_then(|i| self.id_at(i));
+        let id = ElementId::new(self.replica_id, self.clock + 1);
+        self.clock += text.chars().count() as u64;
+        self.integrate_text(id, origin, text);
+        Operation::InsertText {
+            index,
+            text: text.to_string(),
+            id,
+            origin,
+        }
+    }
+
+    /// Deletes `len` characters starting at a visible position as one
+    /// operation
+    pub fn delete_range(&mut self, index: usize, len: usize) -> Operation {
+        if index + len > self.len() {
+            panic!("Index out of bounds");
+        }
+        let start = match self.id_at(index) {
+            Some(id) => self.elements.position(&id).unwrap_or(0),
+            None => 0,
+        };
+        let ids: Vec<ElementId> = self
+            .elements
+            .iter_from(start)
+            .filter(|e| e.visible)
+            .take(len)
+            .map(|e| e.id)
+            .collect();
+        let stamp = self.next_id();
+        for id in &ids {
+            self.mark_deleted(*id, stamp);
+        }
+        Operation::DeleteRange {
+            index,
+            len,
+            ids,
+            stamp,
+        }
+    }
+
+    /// Merges another RGA state into this one.
+    ///
+    /// Elements this replica collected are not brought back, and neither are
+    /// elements anchored on them, which could only have been inserted
+    /// concurrently with a deletion this replica already considered stable.
     pub fn merge(&mut self, other: RGA) {
-        for elem in other.elements {
-            if !self.elements.iter().any(|e| e.id == elem.id) {
-                self.elements.push(elem);
+        // An element's origin always precedes it, so integrating in document
+        // order guarantees every origin is already known.
+        for elem in &other.elements {
+            if !self.elements.contains(&elem.id) {
+                let orphan = elem
+                    .origin
+                    .is_some_and(|origin| !self.elements.contains(&origin));
+                if orphan || self.collected.includes(&elem.id) {
+                    continue;
+                }
+                self.integrate(Element {
+                    visible: true,
+                    ..elem.clone()
+                });
+            }
+            if !elem.visible {
+                let stamp = other.tombstones.get(&elem.id).copied();
+                self.mark_deleted(elem.id, stamp.unwrap_or(elem.id));
+            }
+        }
+        self.version.merge(&other.version);
+        self.clock = self.clock.max(other.clock);
+    }
+
+    /// Applies an operation produced by another replica.
+    ///
+    /// Operations that were already applied are ignored, and operations whose
+    /// origin or target has not arrived yet are buffered until it does.
+    /// Operations that contradict this replica's state are dropped; use
+    /// [`RGA::try_apply`] to learn about them.
+    pub fn apply(&mut self, op: &Operation) {
+        let _ = self.try_apply(op);
+    }
+
+    /// Like [`RGA::apply`], but fails without changing anything when the
+    /// operation reuses ids this replica knows for other elements, such as
+    /// an `InsertText` overlapping a run already integrated, when its ids
+    /// leap implausibly far past this replica's clock, or when its replica
+    /// already has too many operations waiting for their dependencies
+    pub fn try_apply(&mut self, op: &Operation) -> Result<(), CollaboriError> {
+        let last = op.last_id();
+        if last.counter > self.clock.saturating_add(MAX_CLOCK_LEAP).min(MAX_COUNTER) {
+            return Err(CollaboriError::ProtocolViolation(format!(
+                "operation id {:?} leaps too far past the clock at {}",
+                last, self.clock
+            )));
+        }
+        if !self.apply_ready(op)? {
+            if self.pending.contains(op) {
+                return Ok(());
+            }
+            let waiting = self
+                .pending
+                .iter()
+                .filter(|pending| pending.last_id().replica == last.replica)
+                .count();
+            if waiting >= MAX_PENDING_PER_REPLICA {
+                return Err(CollaboriError::ProtocolViolation(format!(
+                    "replica {} has too many operations waiting for their dependencies",
+                    last.replica
+                )));
+            }
+            self.pending.push(op.clone());
+            return Ok(());
+        }
+        // Each applied operation may unblock buffered ones, which may in turn
+        // unblock others. Buffered operations that turn out to be invalid are
+        // dropped.
+        let mut progressed = true;
+        while progressed && !self.pending.is_empty() {
+            let pending = std::mem::take(&mut self.pending);
+            let before = pending.len();
+            for op in pending {
+                if let Ok(false) = self.apply_ready(&op) {
+                    self.pending.push(op);
+                }
+            }
+            progressed = self.pending.len() < before;
+        }
+        Ok(())
+    }
+
+    /// Returns the visible document
+    pub fn text(&self) -> String {
+        self.to_string()
+    }
+
+    /// Returns the number of visible characters
+    pub fn len(&self) -> usize {
+        self.elements.visible_len()
+    }
+
+    /// Returns true if the document has no visible characters
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the id of the character at a visible position
+    pub fn id_at(&self, index: usize) -> Option<ElementId> {
+        self.elements.visible_id(index)
+    }
+
+    /// Returns the visible position of a character, or `None` if it is
+    /// unknown or has been deleted
+    pub fn index_of(&self, id: &ElementId) -> Option<usize> {
+        if !self.elements.get_by_id(id)?.visible {
+            return None;
+        }
+        self.elements.visible_position(id)
+    }
+
+    /// Returns every insert id and delete stamp this replica has observed
+    pub fn version(&self) -> &VersionVector {
+        &self.version
+    }
+
+    /// Returns the operations a replica that has observed `version` is
+    /// missing: inserts of the elements it hasn't seen, in document order,
+    /// followed by deletions with stamps it hasn't seen. Deletions whose
+    /// tombstones were already collected cannot be recovered.
+    pub fn operations_since(&self, version: &VersionVector) -> Vec<Operation> {
+        let mut inserts = Vec::new();
+        let mut deletes = Vec::new();
+        let mut index = 0;
+        for elem in &self.elements {
+            if !version.includes(&elem.id) {
+                inserts.push(Operation::Insert {
+                    index,
+                    value: elem.value,
+                    id: elem.id,
+                    origin: elem.origin,
+                });
+            }
+            if elem.visible {
+                index += 1;
+            } else if let Some(stamp) = self.tombstones.get(&elem.id) {
+                if !version.includes(stamp) {
+                    deletes.push(Operation::Delete {
+                        index,
+                        id: elem.id,
+                        stamp: *stamp,
+                    });
+                }
+            }
+        }
+        inserts.extend(deletes);
+        inserts
+    }
+
+    /// Returns the number of tombstones currently retained
+    pub fn tombstone_count(&self) -> usize {
+        self.elements.len() - self.elements.visible_len()
+    }
+
+    /// Removes tombstones whose deletion is causally stable.
+    ///
+    /// `replicas` are the version vectors of every other replica still taking
+    /// part in the document, e.g. the clients a sync server knows to be
+    /// connected. A tombstone is removed once this replica and all of them
+    /// have observed its deletion, and every element anchored on it. Those
+    /// elements are anchored on the element before the tombstone instead,
+    /// which places them where they already are; since every replica already
+    /// holds them, none has to place them again, and new inserts outrank them
+    /// just as they outranked the tombstone. Nothing is removed while a
+    /// replica has seen operations this one hasn't, as they may be anchored
+    /// on any tombstone. Returns the number of tombstones removed.
+    pub fn collect_garbage<'a, I>(&mut self, replicas: I) -> usize
+    where
+        I: IntoIterator<Item = &'a VersionVector>,
+    {
+        let mut stable = self.version.clone();
+        for version in replicas {
+            // Operations a replica made before seeing a deletion may be
+            // anchored on the tombstone, so none can be missing here
+            if !self.version.dominates(version) {
+                return 0;
             }
+            stable.intersect(version);
         }
-        self.elements.sort_by_key(|e| e.id.clone());
+        let elements: Vec<Element> = self.elements.iter().cloned().collect();
+        let unstable_anchors: HashSet<ElementId> = elements
+            .iter()
+            .filter(|elem| !stable.includes(&elem.id))
+            .filter_map(|elem| elem.origin)
+            .collect();
+        let collectable = |elem: &Element| {
+            !elem.visible
+                && stable.includes(&elem.id)
+                && !unstable_anchors.contains(&elem.id)
+                && self
+                    .tombstones
+                    .get(&elem.id)
+                    .is_some_and(|stamp| stable.includes(stamp))
+        };
+        if !elements.iter().any(collectable) {
+            self.collected.merge(&stable);
+            return 0;
+        }
+
+        // Origins precede their dependents, so each removed tombstone's
+        // replacement anchor is known before it is needed
+        let mut anchors: HashMap<ElementId, Option<ElementId>> = HashMap::new();
+        let mut kept = Vec::with_capacity(elements.len());
+        let mut last = None;
+        for mut elem in elements {
+            if let Some(anchor) = elem.origin.and_then(|origin| anchors.get(&origin)) {
+                elem.origin = *anchor;
+            }
+            if collectable(&elem) {
+                anchors.insert(elem.id, last);
+            } else {
+                last = Some(elem.id);
+                kept.push(elem);
+            }
+        }
+        for id in anchors.keys() {
+            self.tombstones.remove(id);
+        }
+        self.elements = kept.into_iter().collect();
+        self.collected.merge(&stable);
+        anchors.len()
+    }
+
+    /// Returns the number of remote operations still waiting for their
+    /// dependencies
+    pub fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Applies a remote operation if its dependencies are satisfied, returning
+    /// `false` when it has to wait
+    fn apply_ready(&mut self, op: &Operation) -> Result<bool, CollaboriError> {
+        let ready = match op {
+            Operation::Insert {
+                value, id, origin, ..
+            } => {
+                if self.elements.contains(id) || self.collected.includes(id) {
+                    return Ok(true);
+                }
+                if let Some(origin) = origin {
+                    if !self.elements.contains(origin) {
+                        return self.missing_origin(origin);
+                    }
+                }
+                self.integrate(Element {
+                    id: *id,
+                    origin: *origin,
+                    value: *value,
+                    visible: true,
+                });
+                true
+            }
+            Operation::InsertText {
+                text, id, origin, ..
+            } => {
+                // A retransmitted run may have grown since it was first
+                // received, so only its missing suffix is integrated
+                let known = self.known_prefix(*id, text)?;
+                let Some((offset, _)) = text.char_indices().nth(known) else {
+                    return Ok(true);
+                };
+                let origin = match known {
+                    0 => *origin,
+                    _ => Some(ElementId::new(id.replica, id.counter + known as u64 - 1)),
+                };
+                if let Some(origin) = origin {
+                    if !self.elements.contains(&origin) {
+                        return self.missing_origin(&origin);
+                    }
+                }
+                let first = ElementId::new(id.replica, id.counter + known as u64);
+                self.integrate_text(first, origin, &text[offset..]);
+                true
+            }
+            // A collected element was deleted everywhere already
+            Operation::Delete { id, stamp, .. } => {
+                if !self.elements.contains(id) && !self.collected.includes(id) {
+                    return Ok(false);
+                }
+                self.mark_deleted(*id, *stamp);
+                true
+            }
+            Operation::DeleteRange { ids, stamp, .. } => {
+                if ids
+                    .iter()
+                    .any(|id| !self.elements.contains(id) && !self.collected.includes(id))
+                {
+                    return Ok(false);
+                }
+                for id in ids {
+                    self.mark_deleted(*id, *stamp);
+                }
+                self.version.observe(*stamp);
+                true
+            }
+            Operation::Noop { id } => {
+                self.version.observe(*id);
+                self.clock = self.clock.max(id.counter);
+                true
+            }
+        };
+        Ok(ready)
+    }
+
+    /// Decides what happens to an insert whose origin is missing: it waits
+    /// for the origin, unless the origin was collected. Then the insert was
+    /// made concurrently with a deletion already considered stable, and can
+    /// no longer be placed.
+    fn missing_origin(&self, origin: &ElementId) -> Result<bool, CollaboriError> {
+        if self.collected.includes(origin) {
+            return Err(CollaboriError::ProtocolViolation(format!(
+                "insert anchored on collected element {:?}",
+                origin
+            )));
+        }
+        Ok(false)
+    }
+
+    /// Returns how many leading ids of a text run are already known, failing
+    /// if the run overlaps known ids anywhere else
+    fn known_prefix(&self, id: ElementId, text: &str) -> Result<usize, CollaboriError> {
+        let len = text.chars().count();
+        if id.counter.checked_add(len as u64).is_none() {
+            return Err(CollaboriError::ProtocolViolation(format!(
+                "text run at {:?} overflows its replica's counter",
+                id
+            )));
+        }
+        let known = |i: usize| {
+            let id = ElementId::new(id.replica, id.counter + i as u64);
+            self.elements.contains(&id) || self.collected.includes(&id)
+        };
+        let prefix = (0..len).take_while(|&i| known(i)).count();
+        if (prefix..len).any(known) {
+            return Err(CollaboriError::ProtocolViolation(format!(
+                "text run at {:?} overlaps known elements",
+                id
+            )));
+        }
+        Ok(prefix)
+    }
+
+    /// Turns an element into a tombstone, remembering the earliest stamp
+    /// that deleted it unless it was already collected
+    fn mark_deleted(&mut self, id: ElementId, stamp: ElementId) {
+        if self.elements.set_visible(&id, false).is_some() {
+            let recorded = self.tombstones.entry(id).or_insert(stamp);
+            *recorded = (*recorded).min(stamp);
+        }
+        self.version.observe(stamp);
+        self.clock = self.clock.max(stamp.counter);
+    }
+
+    /// Allocates the next identifier for a local insert or delete
+    fn next_id(&mut self) -> ElementId {
+        self.clock += 1;
+        ElementId::new(self.replica_id, self.clock)
+    }
+
+    /// Integrates a run of characters with consecutive ids, each anchored to
+    /// the one before it
+    fn integrate_text(&mut self, id: ElementId, origin: Option<ElementId>, text: &str) {
+        let mut origin = origin;
+        for (i, value) in text.chars().enumerate() {
+            let id = ElementId::new(id.replica, id.counter + i as u64);
+            self.integrate(Element {
+                id,
+                origin,
+                value,
+                visible: true,
+            });
+            origin = Some(id);
+        }
+    }
+
+    /// Places an element using the RGA rule: right after its origin, skipping
+    /// any element with a greater id. Those are concurrent inserts that win
+    /// the tie, or their descendants, which always carry greater counters.
+    fn integrate(&mut self, elem: Element) {
+        let mut pos = match elem.origin {
+            Some(origin) => {
+                self.elements
+                    .position(&origin)
+                    .expect("Origin must be integrated before its dependents")
+                    + 1
+            }
+            None => 0,
+        };
+        pos += self
+            .elements
+            .iter_from(pos)
+            .take_while(|e| e.id > elem.id)
+            .count();
+        self.clock = self.clock.max(elem.id.counter);
+        self.version.observe(elem.id);
+        self.elements.insert(pos, elem);
+    }
+}
+
+/// Serializes the tombstone map as a list of pairs, since JSON object keys
+/// must be strings
+mod stamp_list {
+    use crate::data::ElementId;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::collections::HashMap;
+
+    pub fn serialize<S: Serializer>(
+        stamps: &HashMap<ElementId, ElementId>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(stamps.iter())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<ElementId, ElementId>, D::Error> {
+        let pairs = Vec::<(ElementId, ElementId)>::deserialize(deserializer)?;
+        Ok(pairs.into_iter().collect())
+    }
+}
+
+/// Elements are written as runs, each a stretch of consecutive ids from one
+/// replica where every element's origin is its predecessor, as typing or
+/// `insert_text` produces. A run stores its first id, origin, visibility and
+/// text.
+impl Encode for RGA {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.replica_id.encode(out);
+        self.clock.encode(out);
+
+        let mut runs: Vec<(ElementId, Option<ElementId>, bool, String)> = Vec::new();
+        let mut last: Option<ElementId> = None;
+        for elem in &self.elements {
+            let continues = match (runs.last(), last) {
+                (Some((first, _, visible, _)), Some(prev)) => {
+                    elem.id.replica == first.replica
+                        && elem.id.counter == prev.counter + 1
+                        && elem.origin == Some(prev)
+                        && elem.visible == *visible
+                }
+                _ => false,
+            };
+            match runs.last_mut() {
+                Some((_, _, _, text)) if continues => text.push(elem.value),
+                _ => runs.push((elem.id, elem.origin, elem.visible, elem.value.to_string())),
+            }
+            last = Some(elem.id);
+        }
+        runs.len().encode(out);
+        for (id, origin, visible, text) in &runs {
+            id.encode(out);
+            origin.encode(out);
+            visible.encode(out);
+            text.encode(out);
+        }
+
+        self.pending.encode(out);
+        self.version.encode(out);
+        let tombstones: Vec<(ElementId, ElementId)> = self
+            .tombstones
+            .iter()
+            .map(|(id, stamp)| (*id, *stamp))
+            .collect();
+        tombstones.encode(out);
+        self.collected.encode(out);
+    }
+}
+
+impl Decode for RGA {
+    fn decode(input: &mut &[u8]) -> Result<Self, CollaboriError> {
+        let mut rga = RGA::with_replica_id(u64::decode(input)?);
+        rga.clock = u64::decode(input)?;
+
+        let mut elements = Vec::new();
+        for _ in 0..usize::decode(input)? {
+            let first = ElementId::decode(input)?;
+            let mut origin = Option::<ElementId>::decode(input)?;
+            let visible = bool::decode(input)?;
+            for (i, value) in String::decode(input)?.chars().enumerate() {
+                let counter = first
+                    .counter
+                    .checked_add(i as u64)
+                    .ok_or_else(|| crate::codec::malformed("counter overflow"))?;
+                let id = ElementId::new(first.replica, counter);
+                elements.push(Element {
+                    id,
+                    origin,
+                    value,
+                    visible,
+                });
+                origin = Some(id);
+            }
+        }
+        rga.elements = ElementTree::from_elements(elements)?;
+
+        rga.pending = Vec::decode(input)?;
+        rga.version = VersionVector::decode(input)?;
+        rga.tombstones = Vec::<(ElementId, ElementId)>::decode(input)?
+            .into_iter()
+            .collect();
+        rga.collected = VersionVector::decode(input)?;
+        Ok(rga)
+    }
+}
+
+impl fmt::Display for RGA {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.elements
+            .iter()
+            .filter(|e| e.visible)
+            .try_for_each(|e| write!(f, "{}", e.value))
+    }
+}
+
+impl CRDT for RGA {
+    fn insert(&mut self, index: usize, value: char) -> Operation {
+        RGA::insert(self, index, value)
+    }
+
+    fn delete(&mut self, index: usize) -> Operation {
+        RGA::delete(self, index)
+    }
+
+    fn merge(&mut self, other: Self) {
+        RGA::merge(self, other)
+    }
+
+    fn apply(&mut self, op: &Operation) {
+        RGA::apply(self, op)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ot::OT;
 
     #[test]
     fn test_insert() {
@@ -96,4 +725,591 @@ mod tests {
         rga1.merge(rga2.clone());
         assert_eq!(rga1.elements.len(), 2);
     }
+
+    #[test]
+    fn test_merge_converges_in_both_directions() {
+        let mut base = RGA::with_replica_id(1);
+        base.insert(0, 'x');
+
+        let mut rga1 = base.clone();
+        let mut rga2 = base.clone();
+        rga2.replica_id = 2;
+        for (i, c) in "ab".chars().enumerate() {
+            rga1.insert(1 + i, c);
+        }
+        for (i, c) in "cd".chars().enumerate() {
+            rga2.insert(1 + i, c);
+        }
+
+        let mut merged1 = rga1.clone();
+        merged1.merge(rga2.clone());
+        let mut merged2 = rga2.clone();
+        merged2.merge(rga1.clone());
+
+        assert_eq!(merged1.text(), merged2.text());
+        // Each replica's run stays contiguous instead of interleaving
+        let merged = merged1.text();
+        assert!(merged == "xabcd" || merged == "xcdab", "got {}", merged);
+    }
+
+    #[test]
+    fn test_merge_keeps_deletions() {
+        let mut rga1 = RGA::with_replica_id(1);
+        rga1.insert(0, 'a');
+        rga1.insert(1, 'b');
+        let mut rga2 = rga1.clone();
+        rga2.replica_id = 2;
+        rga2.delete(0);
+
+        rga1.merge(rga2);
+        assert_eq!(rga1.text(), "b");
+    }
+
+    #[test]
+    fn test_insert_after_merge_wins_over_known_elements() {
+        let mut rga1 = RGA::with_replica_id(1);
+        let mut rga2 = RGA::with_replica_id(2);
+        rga2.insert(0, 'b');
+        rga2.insert(1, 'c');
+
+        rga1.merge(rga2.clone());
+        // The local clock has caught up, so a new insert at the head is
+        // ordered before 'b' on every replica.
+        rga1.insert(0, 'a');
+        rga2.merge(rga1.clone());
+
+        assert_eq!(rga1.text(), "abc");
+        assert_eq!(rga2.text(), "abc");
+    }
+
+    #[test]
+    fn test_apply_remote_operations() {
+        let mut local = RGA::with_replica_id(1);
+        let mut remote = RGA::with_replica_id(2);
+        let ops = [
+            remote.insert(0, 'h'),
+            remote.insert(1, 'i'),
+            remote.delete(0),
+        ];
+
+        for op in &ops {
+            local.apply(op);
+        }
+        assert_eq!(local.text(), "i");
+        assert_eq!(local.elements[1].id, *ops[1].id());
+    }
+
+    #[test]
+    fn test_apply_is_idempotent() {
+        let mut local = RGA::with_replica_id(1);
+        let mut remote = RGA::with_replica_id(2);
+        let op = remote.insert(0, 'a');
+
+        local.apply(&op);
+        local.apply(&op);
+        assert_eq!(local.elements.len(), 1);
+    }
+
+    #[test]
+    fn test_apply_buffers_until_dependencies_arrive() {
+        let mut local = RGA::with_replica_id(1);
+        let mut remote = RGA::with_replica_id(2);
+        let insert_a = remote.insert(0, 'a');
+        let insert_b = remote.insert(1, 'b');
+        let delete_a = remote.delete(0);
+
+        local.apply(&delete_a);
+        local.apply(&insert_b);
+        local.apply(&insert_b);
+        assert_eq!(local.pending_len(), 2);
+        assert_eq!(local.text(), "");
+
+        local.apply(&insert_a);
+        assert_eq!(local.pending_len(), 0);
+        assert_eq!(local.text(), "b");
+    }
+
+    #[test]
+    fn test_apply_rejects_runaway_counters() {
+        let mut local = RGA::with_replica_id(1);
+        let runaway = Operation::Insert {
+            index: 0,
+            value: 'a',
+            id: ElementId::new(2, u64::MAX),
+            origin: None,
+        };
+        assert!(matches!(
+            local.try_apply(&runaway),
+            Err(CollaboriError::ProtocolViolation(_))
+        ));
+        let run = Operation::InsertText {
+            index: 0,
+            text: "ab".to_string(),
+            id: ElementId::new(2, u64::MAX),
+            origin: None,
+        };
+        assert!(local.try_apply(&run).is_err());
+        assert!(local.is_empty());
+
+        // The clock is untouched, so local ids keep counting normally
+        assert_eq!(*local.insert(0, 'x').id(), ElementId::new(1, 1));
+    }
+
+    #[test]
+    fn test_pending_operations_are_capped_per_replica() {
+        let mut local = RGA::with_replica_id(1);
+        let orphan = |replica, counter| Operation::Insert {
+            index: 0,
+            value: 'a',
+            id: ElementId::new(replica, counter),
+            origin: Some(ElementId::new(9, 1)),
+        };
+        for counter in 1..=MAX_PENDING_PER_REPLICA as u64 {
+            local.try_apply(&orphan(2, counter)).unwrap();
+        }
+        let overflow = orphan(2, MAX_PENDING_PER_REPLICA as u64 + 1);
+        assert!(matches!(
+            local.try_apply(&overflow),
+            Err(CollaboriError::ProtocolViolation(_))
+        ));
+        assert_eq!(local.pending_len(), MAX_PENDING_PER_REPLICA);
+
+        // Other replicas still get to wait for their dependencies
+        local.try_apply(&orphan(3, 1)).unwrap();
+        assert_eq!(local.pending_len(), MAX_PENDING_PER_REPLICA + 1);
+    }
+
+    #[test]
+    fn test_apply_noop_advances_version() {
+        let mut local = RGA::with_replica_id(1);
+        let mut remote = RGA::with_replica_id(2);
+        let mut other = RGA::with_replica_id(3);
+        let insert = remote.insert(0, 'a');
+        other.apply(&insert);
+        local.apply(&insert);
+        let delete = remote.delete(0);
+        let concurrent = other.delete(0);
+
+        // The delete is cancelled by a concurrent one, but its stamp is
+        // still seen so the remote replica's counters leave no gap
+        let noop = OT::transform(&delete, &concurrent);
+        let noop = noop.as_slice();
+        assert_eq!(
+            noop,
+            [Operation::Noop {
+                id: delete.last_id()
+            }]
+        );
+        local.apply(&concurrent);
+        assert!(!local.version().includes(&delete.last_id()));
+        local.apply(&noop[0]);
+        assert!(local.version().includes(&delete.last_id()));
+    }
+
+    #[test]
+    fn test_positions_skip_tombstones() {
+        let mut rga = RGA::with_replica_id(1);
+        for (i, c) in "abcd".chars().enumerate() {
+            rga.insert(i, c);
+        }
+        rga.delete(1);
+        assert_eq!(rga.text(), "acd");
+        assert_eq!(rga.len(), 3);
+
+        // Position 1 now refers to 'c', not to the tombstone of 'b'
+        let op = rga.delete(1);
+        assert_eq!(rga.to_string(), "ad");
+        assert_eq!(rga.index_of(op.id()), None);
+
+        rga.insert(1, 'x');
+        assert_eq!(rga.text(), "axd");
+        let d = rga.id_at(2).unwrap();
+        assert_eq!(rga.index_of(&d), Some(2));
+        assert_eq!(rga.id_at(3), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Index out of bounds")]
+    fn test_insert_past_visible_end_panics() {
+        let mut rga = RGA::new();
+        rga.insert(0, 'a');
+        rga.delete(0);
+        rga.insert(1, 'b');
+    }
+
+    #[test]
+    fn test_collect_garbage_waits_for_every_replica() {
+        let mut rga1 = RGA::with_replica_id(1);
+        let mut rga2 = RGA::with_replica_id(2);
+        for (i, c) in "abc".chars().enumerate() {
+            let op = rga1.insert(i, c);
+            rga2.apply(&op);
+        }
+        let delete = rga1.delete(1);
+
+        // rga2 has not seen the deletion yet
+        assert_eq!(rga1.collect_garbage([rga2.version()]), 0);
+        assert_eq!(rga1.tombstone_count(), 1);
+
+        rga2.apply(&delete);
+        assert_eq!(rga1.collect_garbage([rga2.version()]), 1);
+        assert_eq!(rga2.collect_garbage([rga1.version()]), 1);
+        assert_eq!(rga1.elements.len(), 2);
+        assert_eq!(rga1.text(), "ac");
+
+        // Late duplicates of collected operations are recognised
+        rga1.apply(&delete);
+        assert_eq!(rga1.pending_len(), 0);
+        assert_eq!(rga1.elements.len(), 2);
+        assert_eq!(rga1.text(), "ac");
+
+        // Editing continues normally after collection
+        let op = rga2.insert(1, 'x');
+        rga1.apply(&op);
+        assert_eq!(rga1.text(), "axc");
+        assert_eq!(rga2.text(), "axc");
+    }
+
+    #[test]
+    fn test_merge_does_not_resurrect_collected_elements() {
+        let mut rga1 = RGA::with_replica_id(1);
+        rga1.insert(0, 'a');
+        rga1.insert(1, 'b');
+        let stale = rga1.clone();
+        let mut rga2 = rga1.clone();
+        rga2.replica_id = 2;
+        rga2.apply(&rga1.delete(0));
+
+        rga1.collect_garbage([rga2.version()]);
+        rga1.merge(stale);
+        assert_eq!(rga1.elements.len(), 1);
+        assert_eq!(rga1.text(), "b");
+    }
+
+    #[test]
+    fn test_collect_garbage_shrinks_snapshots() {
+        let mut rga1 = RGA::with_replica_id(1);
+        let mut rga2 = RGA::with_replica_id(2);
+        let text: String = (0..100).map(|i| char::from(b'a' + i % 26)).collect();
+        rga2.apply(&rga1.insert_text(0, &text));
+        let before = crate::codec::to_bytes(&rga1).len();
+
+        // Deleting the start of a run leaves the rest anchored on tombstones
+        rga2.apply(&rga1.delete_range(0, 90));
+        assert_eq!(rga1.collect_garbage([rga2.version()]), 90);
+        assert_eq!(rga1.elements.len(), 10);
+        assert_eq!(rga1.tombstone_count(), 0);
+        assert!(crate::codec::to_bytes(&rga1).len() < before / 4);
+        assert!(serde_json::to_value(&rga1).unwrap()["elements"]
+            .as_array()
+            .is_some_and(|elements| elements.len() == 10));
+
+        // The snapshot still rebuilds the document, and both sides keep
+        // converging
+        let mut fresh = RGA::with_replica_id(3);
+        fresh.merge(crate::codec::from_bytes(&crate::codec::to_bytes(&rga1)).unwrap());
+        assert_eq!(fresh.text(), rga1.text());
+        let insert = rga2.insert(0, 'Q');
+        for rga in [&mut rga1, &mut fresh] {
+            rga.try_apply(&insert).unwrap();
+        }
+        assert_eq!(rga1.text(), rga2.text());
+        assert_eq!(fresh.text(), rga2.text());
+    }
+
+    #[test]
+    fn test_collect_garbage_waits_for_anchored_elements() {
+        let mut rga1 = RGA::with_replica_id(1);
+        let mut rga2 = RGA::with_replica_id(2);
+        rga2.apply(&rga1.insert_text(0, "xyz"));
+
+        // rga2 types after 'y' while rga1 deletes it
+        let delete = rga1.delete(1);
+        let insert = rga2.insert(2, 'Q');
+        rga2.apply(&delete);
+        // rga1 hasn't seen 'Q' yet, so it can't tell it depends on 'y'
+        assert_eq!(rga1.collect_garbage([rga2.version()]), 0);
+        rga1.apply(&insert);
+        assert_eq!(rga1.text(), "xQz");
+
+        // Once both have seen it, 'y' goes and 'Q' stays where it is
+        assert_eq!(rga1.collect_garbage([rga2.version()]), 1);
+        assert_eq!(rga1.text(), "xQz");
+        assert_eq!(rga1.tombstone_count(), 0);
+        let mut fresh = RGA::with_replica_id(3);
+        fresh.merge(rga1.clone());
+        assert_eq!(fresh.text(), "xQz");
+
+        // Versions that only count what was sent to a replica, as a server
+        // knows them, miss inserts still on their way. One made before its
+        // origin's deletion arrived can't be placed once the origin is gone
+        let mut late = RGA::with_replica_id(4);
+        late.merge(rga1.clone());
+        let orphan = late.insert(3, '!');
+        late.apply(&rga1.delete(2));
+        assert_eq!(rga1.collect_garbage([late.version()]), 0);
+        assert_eq!(rga1.collect_garbage([]), 1);
+        assert!(matches!(
+            rga1.try_apply(&orphan),
+            Err(CollaboriError::ProtocolViolation(_))
+        ));
+        let Operation::Insert { origin, .. } = &orphan else {
+            unreachable!()
+        };
+        assert!(!rga1.elements.contains(origin.as_ref().unwrap()));
+    }
+
+    #[test]
+    fn test_collect_garbage_keeps_replicas_converging() {
+        // Replicas edit concurrently in rounds, then exchange everything;
+        // replica 0 collects garbage after every round
+        let mut replicas: Vec<RGA> = (0..3).map(RGA::with_replica_id).collect();
+        let mut state = 11u64;
+        let mut random = |bound: usize| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (state >> 33) as usize % bound.max(1)
+        };
+        for _ in 0..40 {
+            let mut ops = Vec::new();
+            for (i, rga) in replicas.iter_mut().enumerate() {
+                for _ in 0..random(4) {
+                    let op = if rga.len() > 4 && random(3) == 0 {
+                        let index = random(rga.len() - 2);
+                        rga.delete_range(index, 1 + random(2))
+                    } else {
+                        let index = random(rga.len() + 1);
+                        rga.insert_text(index, ["a", "bc", "def"][random(3)])
+                    };
+                    ops.push((i, op));
+                }
+            }
+            for (origin, op) in &ops {
+                for (i, rga) in replicas.iter_mut().enumerate() {
+                    if i != *origin {
+                        rga.try_apply(op).unwrap();
+                    }
+                }
+            }
+            let versions: Vec<VersionVector> = replicas[1..]
+                .iter()
+                .map(|rga| rga.version().clone())
+                .collect();
+            replicas[0].collect_garbage(&versions);
+
+            let text = replicas[1].text();
+            for rga in &replicas {
+                assert_eq!(rga.text(), text);
+            }
+            let mut fresh = RGA::with_replica_id(9);
+            fresh.merge(replicas[0].clone());
+            assert_eq!(fresh.text(), text);
+        }
+        assert!(replicas[0].elements.len() < replicas[1].elements.len());
+    }
+
+    #[test]
+    fn test_tombstone_stamps_survive_serialization() {
+        let mut rga = RGA::with_replica_id(1);
+        rga.insert(0, 'a');
+        rga.delete(0);
+        let json = serde_json::to_string(&rga).unwrap();
+        let mut restored: RGA = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.collect_garbage([]), 1);
+        assert!(restored.elements.is_empty());
+    }
+
+    #[test]
+    fn test_snapshots_with_duplicate_ids_are_rejected() {
+        let mut rga = RGA::with_replica_id(1);
+        rga.insert_text(0, "ab");
+        let mut json = serde_json::to_value(&rga).unwrap();
+        json["elements"][1]["id"] = json["elements"][0]["id"].clone();
+        assert!(serde_json::from_value::<RGA>(json).is_err());
+
+        // Two runs starting at the same id
+        let mut bytes = Vec::new();
+        1u64.encode(&mut bytes);
+        2u64.encode(&mut bytes);
+        2usize.encode(&mut bytes);
+        for text in ["a", "b"] {
+            ElementId::new(1, 1).encode(&mut bytes);
+            None::<ElementId>.encode(&mut bytes);
+            true.encode(&mut bytes);
+            text.encode(&mut bytes);
+        }
+        Vec::<Operation>::new().encode(&mut bytes);
+        VersionVector::new().encode(&mut bytes);
+        Vec::<(ElementId, ElementId)>::new().encode(&mut bytes);
+        VersionVector::new().encode(&mut bytes);
+        assert!(matches!(
+            crate::codec::from_bytes::<RGA>(&bytes),
+            Err(CollaboriError::DecodeError(_))
+        ));
+    }
+
+    #[test]
+    fn test_snapshots_with_dangling_origins_are_rejected() {
+        // A run anchored on an element the snapshot doesn't hold
+        let mut bytes = Vec::new();
+        1u64.encode(&mut bytes);
+        2u64.encode(&mut bytes);
+        1usize.encode(&mut bytes);
+        ElementId::new(1, 2).encode(&mut bytes);
+        Some(ElementId::new(1, 1)).encode(&mut bytes);
+        true.encode(&mut bytes);
+        "b".encode(&mut bytes);
+        Vec::<Operation>::new().encode(&mut bytes);
+        VersionVector::new().encode(&mut bytes);
+        Vec::<(ElementId, ElementId)>::new().encode(&mut bytes);
+        VersionVector::new().encode(&mut bytes);
+        assert!(matches!(
+            crate::codec::from_bytes::<RGA>(&bytes),
+            Err(CollaboriError::DecodeError(_))
+        ));
+
+        let mut rga = RGA::with_replica_id(1);
+        rga.insert_text(0, "ab");
+        let mut json = serde_json::to_value(&rga).unwrap();
+        json["elements"].as_array_mut().unwrap().reverse();
+        assert!(serde_json::from_value::<RGA>(json).is_err());
+    }
+
+    #[test]
+    fn test_insert_text_and_delete_range() {
+        let mut rga = RGA::with_replica_id(1);
+        rga.insert_text(0, "hello world");
+        let op = rga.delete_range(5, 6);
+        assert_eq!(rga.text(), "hello");
+        match op {
+            Operation::DeleteRange {
+                index, len, ids, ..
+            } => {
+                assert_eq!((index, len, ids.len()), (5, 6, 6));
+            }
+            _ => panic!("Expected DeleteRange operation"),
+        }
+
+        rga.insert_text(5, ", there");
+        rga.delete_range(0, 1);
+        assert_eq!(rga.text(), "ello, there");
+    }
+
+    #[test]
+    fn test_insert_empty_text() {
+        let mut rga1 = RGA::with_replica_id(1);
+        let mut rga2 = RGA::with_replica_id(2);
+        rga2.apply(&rga1.insert_text(0, "ab"));
+
+        // Nothing is inserted, and the op's id isn't reused by the next one
+        let empty = rga1.insert_text(1, "");
+        assert_eq!(
+            empty,
+            Operation::Noop {
+                id: ElementId::new(1, 3)
+            }
+        );
+        assert_eq!(rga1.insert(1, 'x').id(), &ElementId::new(1, 4));
+        rga2.apply(&empty);
+        assert!(rga2.version().includes(&ElementId::new(1, 3)));
+        assert_eq!(rga1.text(), "axb");
+        assert_eq!(rga2.text(), "ab");
+    }
+
+    #[test]
+    fn test_apply_text_operations_out_of_order() {
+        let mut local = RGA::with_replica_id(1);
+        let mut remote = RGA::with_replica_id(2);
+        let insert = remote.insert_text(0, "abcdef");
+        let more = remote.insert_text(3, "XY");
+        let delete = remote.delete_range(1, 4);
+
+        local.apply(&delete);
+        local.apply(&more);
+        assert_eq!(local.pending_len(), 2);
+        local.apply(&insert);
+        local.apply(&insert);
+        assert_eq!(local.pending_len(), 0);
+        assert_eq!(local.text(), remote.text());
+        assert_eq!(local.text(), "adef");
+    }
+
+    #[test]
+    fn test_apply_overlapping_text_runs() {
+        let mut local = RGA::with_replica_id(1);
+        let mut remote = RGA::with_replica_id(2);
+        let op = remote.insert_text(0, "abc");
+        local.apply(&op);
+        local.apply(&op);
+        assert_eq!(local.text(), "abc");
+
+        // A retransmission that grew only integrates what is new
+        let Operation::InsertText { id, origin, .. } = op else {
+            unreachable!()
+        };
+        let grown = Operation::InsertText {
+            index: 0,
+            text: "abcde".to_string(),
+            id,
+            origin,
+        };
+        local.try_apply(&grown).unwrap();
+        local.try_apply(&grown).unwrap();
+        assert_eq!(local.text(), "abcde");
+        assert_eq!(local.elements.len(), 5);
+
+        // Runs overlapping known ids anywhere else are rejected untouched
+        local.apply(&Operation::Insert {
+            index: 5,
+            value: 'g',
+            id: ElementId::new(2, 7),
+            origin: Some(ElementId::new(2, 5)),
+        });
+        let shifted = Operation::InsertText {
+            index: 5,
+            text: "xyz".to_string(),
+            id: ElementId::new(2, 6),
+            origin: Some(ElementId::new(2, 5)),
+        };
+        assert!(matches!(
+            local.try_apply(&shifted),
+            Err(CollaboriError::ProtocolViolation(_))
+        ));
+        local.apply(&shifted);
+        assert_eq!(local.text(), "abcdeg");
+        assert_eq!(local.pending_len(), 0);
+    }
+
+    #[test]
+    fn test_concurrent_text_runs_stay_contiguous() {
+        let mut rga1 = RGA::with_replica_id(1);
+        let mut rga2 = RGA::with_replica_id(2);
+        let op1 = rga1.insert_text(0, "abc");
+        let op2 = rga2.insert_text(0, "xyz");
+        rga1.apply(&op2);
+        rga2.apply(&op1);
+        assert_eq!(rga1.text(), rga2.text());
+        assert!(rga1.text() == "abcxyz" || rga1.text() == "xyzabc");
+    }
+
+    #[test]
+    fn test_operations_since_catches_up_a_stale_replica() {
+        let mut rga1 = RGA::with_replica_id(1);
+        rga1.insert_text(0, "abc");
+        let mut rga2 = rga1.clone();
+        rga2.replica_id = 2;
+
+        // rga2 misses an insert, a delete, and a character typed and deleted
+        rga1.insert(3, 'd');
+        rga1.delete(0);
+        rga1.insert(0, 'x');
+        rga1.delete(0);
+        let missed = rga1.operations_since(rga2.version());
+        assert_eq!(missed.len(), 4);
+        for op in &missed {
+            rga2.apply(op);
+        }
+        assert_eq!(rga2.text(), "bcd");
+        assert_eq!(rga2.version(), rga1.version());
+        assert!(rga1.operations_since(rga2.version()).is_empty());
+    }
 }
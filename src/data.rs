@@ -1,4 +1,5 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
 
 /// Represents a collaborative document
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -7,27 +8,203 @@ pub struct Document {
     pub content: String, // Could be extended to support rich data types like JSON
 }
 
+/// Identifies an element by the replica that created it and that replica's
+/// Lamport counter at creation time.
+///
+/// Ids are ordered by counter first and replica second, which is the priority
+/// RGA uses to order concurrent inserts after the same origin.
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default,
+)]
+pub struct ElementId {
+    pub counter: u64,
+    pub replica: u64,
+}
+
+impl ElementId {
+    pub fn new(replica: u64, counter: u64) -> Self {
+        ElementId { counter, replica }
+    }
+}
+
 /// Represents an operation in the document
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum Operation {
     Insert {
         index: usize,
         value: char,
-        id: String,
+        id: ElementId,
+        origin: Option<ElementId>, // Element the value was inserted after, `None` for the head
     },
     Delete {
         index: usize,
-        id: String,
+        id: ElementId,
+        stamp: ElementId, // Deleting replica's clock tick, used to detect causal stability
+    },
+    InsertText {
+        index: usize,
+        text: String,
+        id: ElementId, // Id of the first character, the others follow with consecutive counters
+        origin: Option<ElementId>,
     },
+    #[serde(deserialize_with = "range_fields")]
+    DeleteRange {
+        index: usize,
+        len: usize,
+        ids: Vec<ElementId>, // Ids of the deleted characters in document order
+        stamp: ElementId,
+    },
+    /// An operation cancelled by transformation, e.g. a delete of a character
+    /// a concurrent delete already removed. Keeps the cancelled operation's
+    /// last id, the stamp of a delete, so replicas applying it still count
+    /// that id as seen.
+    Noop { id: ElementId },
+}
+
+/// Reads a range deletion's fields, rejecting ids that don't cover exactly
+/// the deleted characters
+fn range_fields<'de, D>(
+    deserializer: D,
+) -> Result<(usize, usize, Vec<ElementId>, ElementId), D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct DeleteRange {
+        index: usize,
+        len: usize,
+        ids: Vec<ElementId>,
+        stamp: ElementId,
+    }
+    let range = DeleteRange::deserialize(deserializer)?;
+    if range.ids.len() != range.len {
+        return Err(serde::de::Error::custom(format!(
+            "range deletion of {} characters carries {} ids",
+            range.len,
+            range.ids.len()
+        )));
+    }
+    Ok((range.index, range.len, range.ids, range.stamp))
 }
 
 impl Operation {
-    pub fn id(&self) -> &String {
+    /// Returns the id of the element an operation inserts or deletes; range
+    /// deletions are identified by their stamp
+    pub fn id(&self) -> &ElementId {
         match self {
             Operation::Insert { id, .. } => id,
             Operation::Delete { id, .. } => id,
+            Operation::InsertText { id, .. } => id,
+            Operation::DeleteRange { stamp, .. } => stamp,
+            Operation::Noop { id } => id,
+        }
+    }
+
+    /// Returns the greatest id the operation allocates: the last character
+    /// of an inserted run, or the stamp of a deletion
+    pub fn last_id(&self) -> ElementId {
+        match self {
+            Operation::Insert { id, .. } | Operation::Noop { id } => *id,
+            Operation::InsertText { id, text, .. } => {
+                let len = text.chars().count() as u64;
+                ElementId::new(id.replica, id.counter.saturating_add(len.saturating_sub(1)))
+            }
+            Operation::Delete { stamp, .. } | Operation::DeleteRange { stamp, .. } => *stamp,
+        }
+    }
+
+    /// Returns the visible position the operation applies to, 0 for a no-op
+    pub fn index(&self) -> usize {
+        match self {
+            Operation::Insert { index, .. } => *index,
+            Operation::Delete { index, .. } => *index,
+            Operation::InsertText { index, .. } => *index,
+            Operation::DeleteRange { index, .. } => *index,
+            Operation::Noop { .. } => 0,
+        }
+    }
+
+    /// Returns a copy of the operation targeting another position
+    pub fn with_index(&self, index: usize) -> Operation {
+        let mut op = self.clone();
+        match &mut op {
+            Operation::Insert { index: i, .. } => *i = index,
+            Operation::Delete { index: i, .. } => *i = index,
+            Operation::InsertText { index: i, .. } => *i = index,
+            Operation::DeleteRange { index: i, .. } => *i = index,
+            Operation::Noop { .. } => {}
+        }
+        op
+    }
+}
+
+/// Tracks the highest counter observed from each replica.
+///
+/// Counters from one replica are assumed to be observed in order, which the
+/// sync server guarantees by relaying each connection's operations in order.
+///
+/// Serialized as `(replica, counter)` pairs, since integer map keys don't
+/// survive being buffered inside tagged messages.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(from = "Vec<(u64, u64)>", into = "Vec<(u64, u64)>")]
+pub struct VersionVector(HashMap<u64, u64>);
+
+impl From<Vec<(u64, u64)>> for VersionVector {
+    fn from(entries: Vec<(u64, u64)>) -> Self {
+        VersionVector(entries.into_iter().collect())
+    }
+}
+
+impl From<VersionVector> for Vec<(u64, u64)> {
+    fn from(version: VersionVector) -> Self {
+        version.0.into_iter().collect()
+    }
+}
+
+impl VersionVector {
+    pub fn new() -> Self {
+        VersionVector(HashMap::new())
+    }
+
+    /// Returns the highest counter observed from a replica
+    pub fn get(&self, replica: u64) -> u64 {
+        self.0.get(&replica).copied().unwrap_or(0)
+    }
+
+    /// Records that an id has been observed
+    pub fn observe(&mut self, id: ElementId) {
+        let counter = self.0.entry(id.replica).or_insert(0);
+        *counter = (*counter).max(id.counter);
+    }
+
+    /// Returns true if the id has been observed
+    pub fn includes(&self, id: &ElementId) -> bool {
+        id.counter <= self.get(id.replica)
+    }
+
+    /// Raises every entry to the maximum of both vectors
+    pub fn merge(&mut self, other: &VersionVector) {
+        for (&replica, &counter) in &other.0 {
+            self.observe(ElementId::new(replica, counter));
         }
     }
+
+    /// Returns true if every id `other` has observed was observed here too
+    pub fn dominates(&self, other: &VersionVector) -> bool {
+        other
+            .0
+            .iter()
+            .all(|(&replica, &counter)| counter <= self.get(replica))
+    }
+
+    /// Lowers every entry to the minimum of both vectors, keeping only what
+    /// both have observed
+    pub fn intersect(&mut self, other: &VersionVector) {
+        self.0.retain(|replica, counter| {
+            *counter = (*counter).min(other.get(*replica));
+            *counter > 0
+        });
+    }
 }
 
 /// Represents a user action
@@ -36,3 +213,30 @@ pub struct UserAction {
     pub user_id: String,
     pub operation: Operation,
 }
+
+/// What a client shows others about itself: who it is and where its cursor
+/// is. Ephemeral, relayed to the other clients in a room but never stored.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct Awareness {
+    pub user_id: String, // Same id as in the user's `UserAction`s
+    pub name: String,
+    pub color: String, // CSS color, e.g. "#e91e63"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub selection: Option<Selection>,
+}
+
+/// A selection as visible positions; a plain cursor has `anchor == head`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct Selection {
+    pub anchor: usize, // Where the selection started
+    pub head: usize,   // Where the cursor is
+}
+
+impl Selection {
+    pub fn cursor(index: usize) -> Self {
+        Selection {
+            anchor: index,
+            head: index,
+        }
+    }
+}
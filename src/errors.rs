@@ -1,3 +1,4 @@
+use crate::protocol::ErrorCode;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -11,9 +12,51 @@ pub enum CollaboriError {
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
 
+    #[error("Invalid configuration: {0}")]
+    ConfigError(#[from] toml::de::Error),
+
+    #[error("Unsupported configuration: {0}")]
+    UnsupportedConfig(String),
+
     #[error("Operation not found")]
     OperationNotFound,
 
     #[error("Conflict detected")]
     ConflictDetected,
+
+    #[error("Operation expects a document of length {expected}, got {actual}")]
+    LengthMismatch { expected: usize, actual: usize },
+
+    #[error("Operation length overflows usize")]
+    LengthOverflow,
+
+    #[error("Unknown revision {revision}, the document is at revision {current}")]
+    UnknownRevision { revision: usize, current: usize },
+
+    #[error("Received an acknowledgement with no operation awaiting one")]
+    UnexpectedAck,
+
+    #[error("Protocol violation: {0}")]
+    ProtocolViolation(String),
+
+    #[error("Malformed binary encoding: {0}")]
+    DecodeError(String),
+
+    #[error("Invalid server address: {0}")]
+    InvalidAddress(#[from] url::ParseError),
+
+    #[error("Failed to bind {addr}: {source}")]
+    BindError {
+        addr: String,
+        source: std::io::Error,
+    },
+
+    #[error("Server rejected the handshake ({code:?}): {message}")]
+    HandshakeRejected { code: ErrorCode, message: String },
+
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("Channel closed: {0}")]
+    ChannelClosed(&'static str),
 }
@@ -1,33 +1,43 @@
+pub mod auth;
 pub mod client;
+pub mod codec;
+pub mod config;
 pub mod crdt;
 pub mod data;
+pub mod errors;
 pub mod ot;
+pub mod protocol;
+pub mod store;
 pub mod sync;
+pub mod tree;
 pub mod utils;
 
 use crate::client::SyncClient;
 use crate::data::Operation;
-use crate::sync::SyncManager;
+use crate::errors::CollaboriError;
+use crate::sync::{ServerHandle, SyncManager};
 /// Trait for CRDT algorithms
 pub trait CRDT {
     fn insert(&mut self, index: usize, value: char) -> Operation;
     fn delete(&mut self, index: usize) -> Operation;
     fn merge(&mut self, other: Self);
+    /// Integrates an operation produced by another replica
+    fn apply(&mut self, op: &Operation);
 }
 
 /// Trait for OT algorithms
 pub trait OperationalTransform {
-    fn transform(&self, op_a: &Operation, op_b: &Operation) -> Operation;
+    fn transform(&self, op_a: &Operation, op_b: &Operation) -> Vec<Operation>;
 }
 
-/// Initializes and starts the SyncManager WebSocket server
-pub async fn start_sync_server(addr: &str) {
-    let sync_manager = SyncManager::new();
-    sync_manager.start_server(addr).await;
+/// Initializes and starts the SyncManager WebSocket server, which runs until
+/// the returned handle is shut down or dropped
+pub async fn start_sync_server(addr: &str) -> Result<ServerHandle, CollaboriError> {
+    SyncManager::new().start_server(addr).await
 }
 
 /// Connects to a SyncManager WebSocket server as a client
-pub async fn connect_sync_client(addr: &str) -> SyncClient {
+pub async fn connect_sync_client(addr: &str) -> Result<SyncClient, CollaboriError> {
     SyncClient::connect(addr).await
 }
 
@@ -35,6 +45,7 @@ pub async fn connect_sync_client(addr: &str) -> SyncClient {
 mod tests {
     use super::*;
     use crate::crdt::RGA;
+    use crate::data::ElementId;
     use crate::ot::OT;
 
     #[test]
@@ -46,7 +57,8 @@ mod tests {
             Operation::Insert {
                 index: 0,
                 value: 'a',
-                id: op.id().clone()
+                id: *op.id(),
+                origin: None,
             }
         );
         assert_eq!(rga.elements.len(), 1);
@@ -63,7 +75,8 @@ mod tests {
             op,
             Operation::Delete {
                 index: 0,
-                id: op.id().clone()
+                id: *op.id(),
+                stamp: ElementId::new(rga.replica_id, 2),
             }
         );
         assert!(!rga.elements[0].visible);
@@ -89,15 +102,17 @@ mod tests {
         let op_a = Operation::Insert {
             index: 1,
             value: 'a',
-            id: "1".into(),
+            id: ElementId::new(1, 1),
+            origin: None,
         };
         let op_b = Operation::Insert {
             index: 2,
             value: 'b',
-            id: "2".into(),
+            id: ElementId::new(2, 1),
+            origin: None,
         };
         let result = OT::transform(&op_a, &op_b);
-        assert_eq!(result, op_a);
+        assert_eq!(result, vec![op_a]);
     }
 
     #[test]
@@ -105,42 +120,48 @@ mod tests {
         let op_a = Operation::Insert {
             index: 1,
             value: 'a',
-            id: "1".into(),
+            id: ElementId::new(1, 1),
+            origin: None,
         };
         let op_b = Operation::Delete {
             index: 2,
-            id: "2".into(),
+            id: ElementId::new(2, 1),
+            stamp: ElementId::new(2, 2),
         };
         let result = OT::transform(&op_a, &op_b);
-        assert_eq!(result, op_a);
+        assert_eq!(result, vec![op_a]);
     }
 
     #[test]
     fn test_ot_transform_delete_insert() {
         let op_a = Operation::Delete {
             index: 1,
-            id: "1".into(),
+            id: ElementId::new(1, 1),
+            stamp: ElementId::new(1, 2),
         };
         let op_b = Operation::Insert {
             index: 2,
             value: 'b',
-            id: "2".into(),
+            id: ElementId::new(2, 1),
+            origin: None,
         };
         let result = OT::transform(&op_a, &op_b);
-        assert_eq!(result, op_a);
+        assert_eq!(result, vec![op_a]);
     }
 
     #[test]
     fn test_ot_transform_delete_delete() {
         let op_a = Operation::Delete {
             index: 1,
-            id: "1".into(),
+            id: ElementId::new(1, 1),
+            stamp: ElementId::new(1, 2),
         };
         let op_b = Operation::Delete {
             index: 2,
-            id: "2".into(),
+            id: ElementId::new(2, 1),
+            stamp: ElementId::new(2, 2),
         };
         let result = OT::transform(&op_a, &op_b);
-        assert_eq!(result, op_a);
+        assert_eq!(result, vec![op_a]);
     }
 }
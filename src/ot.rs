@@ -1,102 +1,639 @@
-use crate::data::Operation;
-use serde::{Deserialize, Serialize};
+use crate::data::{ElementId, Operation};
+use crate::errors::CollaboriError;
+use serde::de::Error as _;
+use serde::ser::SerializeSeq;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::cmp::Ordering;
 
 /// Operational Transformation (OT) implementation
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct OT;
 
+/// Extent of an operation in the document it applies to
+enum Span {
+    Insert { index: usize, len: usize },
+    Delete { index: usize, len: usize },
+}
+
+impl Span {
+    /// Returns the span of an operation, or `None` for a no-op
+    fn of(op: &Operation) -> Option<Span> {
+        Some(match op {
+            Operation::Insert { index, .. } => Span::Insert {
+                index: *index,
+                len: 1,
+            },
+            Operation::InsertText { index, text, .. } => Span::Insert {
+                index: *index,
+                len: text.chars().count(),
+            },
+            Operation::Delete { index, .. } => Span::Delete {
+                index: *index,
+                len: 1,
+            },
+            Operation::DeleteRange { index, len, .. } => Span::Delete {
+                index: *index,
+                len: *len,
+            },
+            Operation::Noop { .. } => return None,
+        })
+    }
+}
+
 impl OT {
-    /// Transforms operation a against operation b
-    pub fn transform(op_a: &Operation, op_b: &Operation) -> Operation {
-        match (op_a, op_b) {
-            (Operation::Insert { index: idx_a, .. }, Operation::Insert { index: idx_b, .. }) => {
-                match idx_a.cmp(idx_b) {
-                    Ordering::Less => op_a.clone(),
-                    Ordering::Greater => match op_a {
-                        Operation::Insert { value, id, .. } => Operation::Insert {
-                            index: idx_a + 1,
-                            value: *value,
-                            id: id.clone(),
-                        },
-                        _ => op_a.clone(),
-                    },
-                    Ordering::Equal => match op_a.id().cmp(op_b.id()) {
-                        Ordering::Less => op_a.clone(),
-                        Ordering::Greater => match op_a {
-                            Operation::Insert { value, id, .. } => Operation::Insert {
-                                index: idx_a + 1,
-                                value: *value,
-                                id: id.clone(),
-                            },
-                            _ => op_a.clone(),
-                        },
-                        Ordering::Equal => op_a.clone(),
-                    },
+    /// Transforms operation a against operation b, so that applying b and
+    /// then the result has the same effect as applying a and then b
+    /// transformed against a.
+    ///
+    /// Usually yields a single operation. A range delete that a concurrent
+    /// insert lands inside is split into the parts before and after the
+    /// inserted text, and a delete whose characters b already removed turns
+    /// into `Operation::Noop`.
+    pub fn transform(op_a: &Operation, op_b: &Operation) -> Vec<Operation> {
+        let (span_a, span_b) = match (Span::of(op_a), Span::of(op_b)) {
+            (Some(span_a), Some(span_b)) => (span_a, span_b),
+            // No-ops neither move nor are moved by anything
+            _ => return vec![op_a.clone()],
+        };
+        match (span_a, span_b) {
+            (Span::Insert { index: p, .. }, Span::Insert { index: q, len: m }) => {
+                // Concurrent inserts at the same position are ordered by id
+                let shift = match p.cmp(&q) {
+                    Ordering::Less => false,
+                    Ordering::Greater => true,
+                    Ordering::Equal => op_a.id() > op_b.id(),
+                };
+                vec![if shift {
+                    op_a.with_index(p + m)
+                } else {
+                    op_a.clone()
+                }]
+            }
+            (Span::Insert { index: p, .. }, Span::Delete { index: q, len: k }) => {
+                // An insert inside the deleted range survives at its start
+                vec![op_a.with_index(if p <= q {
+                    p
+                } else {
+                    q.max(p.saturating_sub(k))
+                })]
+            }
+            (Span::Delete { index: p, len: l }, Span::Insert { index: q, len: m }) => {
+                if q <= p {
+                    vec![op_a.with_index(p + m)]
+                } else if q >= p + l {
+                    vec![op_a.clone()]
+                } else {
+                    // Delete around the inserted text; the second part has
+                    // moved past the text and the already deleted first part
+                    vec![
+                        Self::delete_part(op_a, p, 0, q - p),
+                        Self::delete_part(op_a, p + m, q - p, p + l - q),
+                    ]
                 }
             }
-            (Operation::Insert { index: idx_a, .. }, Operation::Delete { index: idx_b, .. }) => {
-                match idx_a.cmp(idx_b) {
-                    Ordering::Less | Ordering::Equal => op_a.clone(),
-                    Ordering::Greater => match op_a {
-                        Operation::Insert { value, id, .. } => Operation::Insert {
-                            index: idx_a - 1,
-                            value: *value,
-                            id: id.clone(),
-                        },
-                        _ => op_a.clone(),
-                    },
+            (Span::Delete { index: p, len: l }, Span::Delete { index: q, len: k }) => {
+                let start = p.max(q);
+                let overlap = (p + l).min(q + k).saturating_sub(start);
+                if overlap == l {
+                    return vec![Operation::Noop { id: op_a.last_id() }];
+                }
+                // Characters b deleted before a's range shift it left
+                let index = p - k.min(p.saturating_sub(q));
+                if overlap == 0 {
+                    return vec![op_a.with_index(index)];
+                }
+                let mut ids = Self::delete_ids(op_a, 0, l);
+                if !ids.is_empty() {
+                    ids.drain(start - p..start - p + overlap);
                 }
+                vec![Self::delete_with_ids(op_a, index, l - overlap, ids)]
             }
-            (Operation::Delete { index: idx_a, .. }, Operation::Insert { index: idx_b, .. }) => {
-                match idx_a.cmp(idx_b) {
-                    Ordering::Less => op_a.clone(),
-                    Ordering::Greater => match op_a {
-                        Operation::Delete { id, .. } => Operation::Delete {
-                            index: idx_a + 1,
-                            id: id.clone(),
-                        },
-                        _ => op_a.clone(),
-                    },
-                    Ordering::Equal => op_a.clone(),
+        }
+    }
+
+    /// Builds a range delete at `index` covering `len` characters of `op`,
+    /// starting `offset` characters into it
+    fn delete_part(op: &Operation, index: usize, offset: usize, len: usize) -> Operation {
+        Self::delete_with_ids(op, index, len, Self::delete_ids(op, offset, len))
+    }
+
+    fn delete_with_ids(op: &Operation, index: usize, len: usize, ids: Vec<ElementId>) -> Operation {
+        match op {
+            Operation::DeleteRange { stamp, .. } => Operation::DeleteRange {
+                index,
+                len,
+                ids,
+                stamp: *stamp,
+            },
+            _ => op.with_index(index),
+        }
+    }
+
+    /// Returns the ids of `len` deleted characters starting at `offset`, or
+    /// none if the operation does not carry ids
+    fn delete_ids(op: &Operation, offset: usize, len: usize) -> Vec<ElementId> {
+        match op {
+            Operation::DeleteRange { ids, .. } => ids
+                .get(offset..offset + len)
+                .map(|ids| ids.to_vec())
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// One step of a `TextOperation`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Component {
+    Retain(usize),
+    Insert(String),
+    Delete(usize),
+}
+
+/// Compound text operation: a sequence of retain, insert and delete steps
+/// covering the whole document, as spoken by ot.js and CodeMirror.
+///
+/// Lengths are counted in characters. On the wire the operation is an array
+/// in the ot.js format: positive numbers retain, negative numbers delete and
+/// strings insert.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TextOperation {
+    components: Vec<Component>,
+    base_len: usize,
+    target_len: usize,
+}
+
+impl TextOperation {
+    /// Creates an empty operation
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds the compound form of a single operation on a document of
+    /// `base_len` characters
+    pub fn from_operation(op: &Operation, base_len: usize) -> Self {
+        let index = op.index();
+        let (inserted, deleted) = match op {
+            Operation::Insert { value, .. } => (value.to_string(), 0),
+            Operation::InsertText { text, .. } => (text.clone(), 0),
+            Operation::Delete { .. } => (String::new(), 1),
+            Operation::DeleteRange { len, .. } => (String::new(), *len),
+            Operation::Noop { .. } => (String::new(), 0),
+        };
+        TextOperation::new()
+            .retain(index)
+            .insert(&inserted)
+            .delete(deleted)
+            .retain(base_len.saturating_sub(index + deleted))
+    }
+
+    /// Skips over `n` characters.
+    ///
+    /// Panics if the operation's lengths overflow; see
+    /// [`TextOperation::try_retain`].
+    pub fn retain(self, n: usize) -> Self {
+        self.try_retain(n)
+            .expect("Operation length overflows usize")
+    }
+
+    /// Skips over `n` characters, failing if the operation's lengths overflow
+    pub fn try_retain(mut self, n: usize) -> Result<Self, CollaboriError> {
+        if n == 0 {
+            return Ok(self);
+        }
+        self.base_len = grow(self.base_len, n)?;
+        self.target_len = grow(self.target_len, n)?;
+        if let Some(Component::Retain(last)) = self.components.last_mut() {
+            *last += n;
+        } else {
+            self.components.push(Component::Retain(n));
+        }
+        Ok(self)
+    }
+
+    /// Inserts a string at the current position.
+    ///
+    /// Panics if the operation's lengths overflow; see
+    /// [`TextOperation::try_insert`].
+    pub fn insert(self, text: &str) -> Self {
+        self.try_insert(text)
+            .expect("Operation length overflows usize")
+    }
+
+    /// Inserts a string at the current position, failing if the operation's
+    /// lengths overflow
+    pub fn try_insert(mut self, text: &str) -> Result<Self, CollaboriError> {
+        if text.is_empty() {
+            return Ok(self);
+        }
+        self.target_len = grow(self.target_len, text.chars().count())?;
+        // Keep inserts ahead of deletes so equal operations have one form
+        let len = self.components.len();
+        match self.components.as_mut_slice() {
+            [.., Component::Insert(last)] => last.push_str(text),
+            [.., Component::Insert(prev), Component::Delete(_)] => prev.push_str(text),
+            [.., Component::Delete(_)] => self
+                .components
+                .insert(len - 1, Component::Insert(text.into())),
+            _ => self.components.push(Component::Insert(text.into())),
+        }
+        Ok(self)
+    }
+
+    /// Deletes `n` characters at the current position.
+    ///
+    /// Panics if the operation's lengths overflow; see
+    /// [`TextOperation::try_delete`].
+    pub fn delete(self, n: usize) -> Self {
+        self.try_delete(n)
+            .expect("Operation length overflows usize")
+    }
+
+    /// Deletes `n` characters at the current position, failing if the
+    /// operation's lengths overflow
+    pub fn try_delete(mut self, n: usize) -> Result<Self, CollaboriError> {
+        if n == 0 {
+            return Ok(self);
+        }
+        self.base_len = grow(self.base_len, n)?;
+        if let Some(Component::Delete(last)) = self.components.last_mut() {
+            *last += n;
+        } else {
+            self.components.push(Component::Delete(n));
+        }
+        Ok(self)
+    }
+
+    pub fn components(&self) -> &[Component] {
+        &self.components
+    }
+
+    /// Length of the document the operation applies to
+    pub fn base_len(&self) -> usize {
+        self.base_len
+    }
+
+    /// Length of the document the operation produces
+    pub fn target_len(&self) -> usize {
+        self.target_len
+    }
+
+    /// Returns true if applying the operation leaves any document unchanged
+    pub fn is_noop(&self) -> bool {
+        self.components
+            .iter()
+            .all(|c| matches!(c, Component::Retain(_)))
+    }
+
+    /// Applies the operation to a document
+    pub fn apply(&self, doc: &str) -> Result<String, CollaboriError> {
+        let mut chars = doc.chars();
+        let actual = chars.clone().count();
+        if actual != self.base_len {
+            return Err(CollaboriError::LengthMismatch {
+                expected: self.base_len,
+                actual,
+            });
+        }
+        let mut result = String::with_capacity(doc.len());
+        for component in &self.components {
+            match component {
+                Component::Retain(n) => result.extend(chars.by_ref().take(*n)),
+                Component::Insert(text) => result.push_str(text),
+                Component::Delete(n) => {
+                    chars.by_ref().take(*n).for_each(drop);
                 }
             }
-            (Operation::Delete { index: idx_a, .. }, Operation::Delete { index: idx_b, .. }) => {
-                match idx_a.cmp(idx_b) {
-                    Ordering::Equal => op_a.clone(),
-                    Ordering::Less => op_a.clone(),
-                    Ordering::Greater => match op_a {
-                        Operation::Delete { id, .. } => Operation::Delete {
-                            index: idx_a - 1,
-                            id: id.clone(),
-                        },
-                        _ => op_a.clone(),
-                    },
+        }
+        Ok(result)
+    }
+
+    /// Returns the operation that undoes this one, given the document it was
+    /// applied to
+    pub fn invert(&self, doc: &str) -> TextOperation {
+        let mut chars = doc.chars();
+        let mut inverse = TextOperation::new();
+        for component in &self.components {
+            inverse = match component {
+                Component::Retain(n) => {
+                    chars.by_ref().take(*n).for_each(drop);
+                    inverse.retain(*n)
                 }
+                Component::Insert(text) => inverse.delete(text.chars().count()),
+                Component::Delete(n) => {
+                    let deleted: String = chars.by_ref().take(*n).collect();
+                    inverse.insert(&deleted)
+                }
+            };
+        }
+        inverse
+    }
+
+    /// Combines two consecutive operations into one with the same effect as
+    /// applying `a` then `b`
+    pub fn compose(a: &TextOperation, b: &TextOperation) -> Result<TextOperation, CollaboriError> {
+        if a.target_len != b.base_len {
+            return Err(CollaboriError::LengthMismatch {
+                expected: b.base_len,
+                actual: a.target_len,
+            });
+        }
+        let mut result = TextOperation::new();
+        let mut ops_a = a.components.iter().cloned();
+        let mut ops_b = b.components.iter().cloned();
+        let mut op_a = ops_a.next();
+        let mut op_b = ops_b.next();
+        loop {
+            match (op_a.take(), op_b.take()) {
+                (None, None) => break,
+                (Some(Component::Delete(n)), next_b) => {
+                    result = result.delete(n);
+                    op_a = ops_a.next();
+                    op_b = next_b;
+                }
+                (next_a, Some(Component::Insert(text))) => {
+                    result = result.insert(&text);
+                    op_a = next_a;
+                    op_b = ops_b.next();
+                }
+                (Some(Component::Retain(n)), Some(Component::Retain(m))) => {
+                    result = result.retain(n.min(m));
+                    (op_a, op_b) = Self::advance(
+                        (n, Component::Retain),
+                        (m, Component::Retain),
+                        &mut ops_a,
+                        &mut ops_b,
+                    );
+                }
+                (Some(Component::Insert(text)), Some(Component::Delete(m))) => {
+                    let n = text.chars().count();
+                    match n.cmp(&m) {
+                        Ordering::Less => {
+                            op_a = ops_a.next();
+                            op_b = Some(Component::Delete(m - n));
+                        }
+                        Ordering::Equal => {
+                            op_a = ops_a.next();
+                            op_b = ops_b.next();
+                        }
+                        Ordering::Greater => {
+                            op_a = Some(Component::Insert(text.chars().skip(m).collect()));
+                            op_b = ops_b.next();
+                        }
+                    }
+                }
+                (Some(Component::Insert(text)), Some(Component::Retain(m))) => {
+                    let n = text.chars().count();
+                    match n.cmp(&m) {
+                        Ordering::Less => {
+                            result = result.insert(&text);
+                            op_a = ops_a.next();
+                            op_b = Some(Component::Retain(m - n));
+                        }
+                        Ordering::Equal => {
+                            result = result.insert(&text);
+                            op_a = ops_a.next();
+                            op_b = ops_b.next();
+                        }
+                        Ordering::Greater => {
+                            let head: String = text.chars().take(m).collect();
+                            result = result.insert(&head);
+                            op_a = Some(Component::Insert(text.chars().skip(m).collect()));
+                            op_b = ops_b.next();
+                        }
+                    }
+                }
+                (Some(Component::Retain(n)), Some(Component::Delete(m))) => {
+                    result = result.delete(n.min(m));
+                    (op_a, op_b) = Self::advance(
+                        (n, Component::Retain),
+                        (m, Component::Delete),
+                        &mut ops_a,
+                        &mut ops_b,
+                    );
+                }
+                _ => unreachable!("Lengths were checked before composing"),
             }
         }
+        Ok(result)
+    }
+
+    /// Transforms two concurrent operations on the same document into
+    /// `(a', b')` such that applying `a` then `b'` equals applying `b` then
+    /// `a'`. When both insert at the same position, `a`'s text comes first.
+    pub fn transform(
+        a: &TextOperation,
+        b: &TextOperation,
+    ) -> Result<(TextOperation, TextOperation), CollaboriError> {
+        if a.base_len != b.base_len {
+            return Err(CollaboriError::LengthMismatch {
+                expected: a.base_len,
+                actual: b.base_len,
+            });
+        }
+        let mut a_prime = TextOperation::new();
+        let mut b_prime = TextOperation::new();
+        let mut ops_a = a.components.iter().cloned();
+        let mut ops_b = b.components.iter().cloned();
+        let mut op_a = ops_a.next();
+        let mut op_b = ops_b.next();
+        loop {
+            match (op_a.take(), op_b.take()) {
+                (None, None) => break,
+                (Some(Component::Insert(text)), next_b) => {
+                    let n = text.chars().count();
+                    a_prime = a_prime.insert(&text);
+                    b_prime = b_prime.retain(n);
+                    op_a = ops_a.next();
+                    op_b = next_b;
+                }
+                (next_a, Some(Component::Insert(text))) => {
+                    let n = text.chars().count();
+                    a_prime = a_prime.retain(n);
+                    b_prime = b_prime.insert(&text);
+                    op_a = next_a;
+                    op_b = ops_b.next();
+                }
+                (Some(Component::Retain(n)), Some(Component::Retain(m))) => {
+                    a_prime = a_prime.retain(n.min(m));
+                    b_prime = b_prime.retain(n.min(m));
+                    (op_a, op_b) = Self::advance(
+                        (n, Component::Retain),
+                        (m, Component::Retain),
+                        &mut ops_a,
+                        &mut ops_b,
+                    );
+                }
+                (Some(Component::Delete(n)), Some(Component::Delete(m))) => {
+                    // Both removed the same characters
+                    (op_a, op_b) = Self::advance(
+                        (n, Component::Delete),
+                        (m, Component::Delete),
+                        &mut ops_a,
+                        &mut ops_b,
+                    );
+                }
+                (Some(Component::Delete(n)), Some(Component::Retain(m))) => {
+                    a_prime = a_prime.delete(n.min(m));
+                    (op_a, op_b) = Self::advance(
+                        (n, Component::Delete),
+                        (m, Component::Retain),
+                        &mut ops_a,
+                        &mut ops_b,
+                    );
+                }
+                (Some(Component::Retain(n)), Some(Component::Delete(m))) => {
+                    b_prime = b_prime.delete(n.min(m));
+                    (op_a, op_b) = Self::advance(
+                        (n, Component::Retain),
+                        (m, Component::Delete),
+                        &mut ops_a,
+                        &mut ops_b,
+                    );
+                }
+                _ => unreachable!("Lengths were checked before transforming"),
+            }
+        }
+        Ok((a_prime, b_prime))
+    }
+
+    /// Consumes the overlap of two counted components, leaving the remainder
+    /// of the longer one in place and advancing past the shorter one
+    fn advance(
+        (n, kind_a): (usize, fn(usize) -> Component),
+        (m, kind_b): (usize, fn(usize) -> Component),
+        ops_a: &mut impl Iterator<Item = Component>,
+        ops_b: &mut impl Iterator<Item = Component>,
+    ) -> (Option<Component>, Option<Component>) {
+        match n.cmp(&m) {
+            Ordering::Less => (ops_a.next(), Some(kind_b(m - n))),
+            Ordering::Equal => (ops_a.next(), ops_b.next()),
+            Ordering::Greater => (Some(kind_a(n - m)), ops_b.next()),
+        }
+    }
+}
+
+/// Adds `n` to an operation's length, failing on overflow
+fn grow(len: usize, n: usize) -> Result<usize, CollaboriError> {
+    len.checked_add(n).ok_or(CollaboriError::LengthOverflow)
+}
+
+impl Serialize for TextOperation {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.components.len()))?;
+        for component in &self.components {
+            match component {
+                Component::Retain(n) => seq.serialize_element(&(*n as i64))?,
+                Component::Insert(text) => seq.serialize_element(text)?,
+                Component::Delete(n) => seq.serialize_element(&-(*n as i64))?,
+            }
+        }
+        seq.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for TextOperation {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Wire {
+            Count(i64),
+            Text(String),
+        }
+
+        let count = |n: i64| usize::try_from(n.unsigned_abs()).map_err(D::Error::custom);
+        let mut op = TextOperation::new();
+        for component in Vec::<Wire>::deserialize(deserializer)? {
+            op = match component {
+                Wire::Count(n) if n >= 0 => op.try_retain(count(n)?),
+                Wire::Count(n) => op.try_delete(count(n)?),
+                Wire::Text(text) => op.try_insert(&text),
+            }
+            .map_err(D::Error::custom)?;
+        }
+        Ok(op)
+    }
+}
+
+/// Server-side state of a document edited through OT: the current text and
+/// every operation applied to it, where an operation's position in the
+/// history is the revision it was applied on.
+#[derive(Debug, Clone, Default)]
+pub struct OTDocument {
+    text: String,
+    history: Vec<TextOperation>,
+}
+
+impl OTDocument {
+    pub fn new(text: &str) -> Self {
+        OTDocument {
+            text: text.to_string(),
+            history: Vec::new(),
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Returns the number of operations applied so far
+    pub fn revision(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Returns the operations applied since `revision`
+    pub fn operations_since(&self, revision: usize) -> Option<&[TextOperation]> {
+        self.history.get(revision..)
+    }
+
+    /// Accepts an operation a client based on `revision`.
+    ///
+    /// The operation is transformed against everything applied since, then
+    /// applied and appended to the history. Returns the transformed
+    /// operation, which other clients need to apply; the new revision is
+    /// `self.revision()`.
+    pub fn receive(
+        &mut self,
+        revision: usize,
+        operation: TextOperation,
+    ) -> Result<TextOperation, CollaboriError> {
+        let concurrent =
+            self.operations_since(revision)
+                .ok_or(CollaboriError::UnknownRevision {
+                    revision,
+                    current: self.revision(),
+                })?;
+        let mut operation = operation;
+        for applied in concurrent {
+            operation = TextOperation::transform(&operation, applied)?.0;
+        }
+        self.text = operation.apply(&self.text)?;
+        self.history.push(operation.clone());
+        Ok(operation)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::data::Operation;
+    use crate::data::{ElementId, Operation};
 
     #[test]
     fn test_transform_insert_insert() {
         let op_a = Operation::Insert {
             index: 1,
             value: 'a',
-            id: "1".into(),
+            id: ElementId::new(1, 1),
+            origin: None,
         };
         let op_b = Operation::Insert {
             index: 2,
             value: 'b',
-            id: "2".into(),
+            id: ElementId::new(2, 1),
+            origin: None,
         };
         let transformed = OT::transform(&op_a, &op_b);
-        assert_eq!(transformed, op_a);
+        assert_eq!(transformed, vec![op_a]);
     }
 
     #[test]
@@ -104,15 +641,17 @@ mod tests {
         let op_a = Operation::Insert {
             index: 3,
             value: 'a',
-            id: "1".into(),
+            id: ElementId::new(1, 1),
+            origin: None,
         };
         let op_b = Operation::Delete {
             index: 2,
-            id: "2".into(),
+            id: ElementId::new(2, 1),
+            stamp: ElementId::new(2, 2),
         };
         let transformed = OT::transform(&op_a, &op_b);
-        if let Operation::Insert { index, .. } = transformed {
-            assert_eq!(index, 2);
+        if let [Operation::Insert { index, .. }] = transformed.as_slice() {
+            assert_eq!(*index, 2);
         } else {
             panic!("Expected Insert operation");
         }
@@ -122,13 +661,324 @@ mod tests {
     fn test_transform_delete_delete() {
         let op_a = Operation::Delete {
             index: 2,
-            id: "1".into(),
+            id: ElementId::new(1, 1),
+            stamp: ElementId::new(1, 2),
         };
         let op_b = Operation::Delete {
             index: 2,
-            id: "2".into(),
+            id: ElementId::new(2, 1),
+            stamp: ElementId::new(2, 2),
         };
+        // Both deleted the same character, so it must only go once. The
+        // no-op keeps a's stamp rather than the character's id
         let transformed = OT::transform(&op_a, &op_b);
-        assert_eq!(transformed, op_a);
+        assert_eq!(
+            transformed,
+            vec![Operation::Noop {
+                id: ElementId::new(1, 2)
+            }]
+        );
+        assert_eq!(assert_converges("abcd", &op_a, &op_b), "abd");
+    }
+
+    #[test]
+    fn test_transform_delete_against_insert_at_same_index() {
+        let op_a = Operation::Delete {
+            index: 2,
+            id: ElementId::new(1, 1),
+            stamp: ElementId::new(1, 2),
+        };
+        let op_b = Operation::Insert {
+            index: 2,
+            value: 'x',
+            id: ElementId::new(2, 1),
+            origin: None,
+        };
+        assert_eq!(OT::transform(&op_a, &op_b), vec![op_a.with_index(3)]);
+        assert_eq!(assert_converges("abcd", &op_a, &op_b), "abxd");
+    }
+
+    fn insert_text(index: usize, text: &str, replica: u64) -> Operation {
+        Operation::InsertText {
+            index,
+            text: text.into(),
+            id: ElementId::new(replica, 1),
+            origin: None,
+        }
+    }
+
+    fn delete_range(index: usize, len: usize, replica: u64) -> Operation {
+        Operation::DeleteRange {
+            index,
+            len,
+            ids: Vec::new(),
+            stamp: ElementId::new(replica, 1),
+        }
+    }
+
+    /// Applies index-based operations to a plain string
+    fn apply(doc: &str, ops: &[Operation]) -> String {
+        let mut chars: Vec<char> = doc.chars().collect();
+        for op in ops {
+            match op {
+                Operation::Insert { index, value, .. } => chars.insert(*index, *value),
+                Operation::InsertText { index, text, .. } => {
+                    chars.splice(*index..*index, text.chars());
+                }
+                Operation::Delete { index, .. } => {
+                    chars.remove(*index);
+                }
+                Operation::DeleteRange { index, len, .. } => {
+                    chars.drain(*index..*index + len);
+                }
+                Operation::Noop { .. } => {}
+            }
+        }
+        chars.into_iter().collect()
+    }
+
+    fn assert_converges(doc: &str, op_a: &Operation, op_b: &Operation) -> String {
+        let mut left = apply(doc, std::slice::from_ref(op_a));
+        left = apply(&left, &OT::transform(op_b, op_a));
+        let mut right = apply(doc, std::slice::from_ref(op_b));
+        right = apply(&right, &OT::transform(op_a, op_b));
+        assert_eq!(left, right, "{:?} and {:?} diverge", op_a, op_b);
+        left
+    }
+
+    #[test]
+    fn test_transform_splits_range_around_insert() {
+        let delete = delete_range(1, 4, 1);
+        let insert = insert_text(3, "XY", 2);
+        let transformed = OT::transform(&delete, &insert);
+        assert_eq!(
+            transformed,
+            vec![delete_range(1, 2, 1), delete_range(3, 2, 1)]
+        );
+        assert_eq!(assert_converges("abcdefg", &delete, &insert), "aXYfg");
+    }
+
+    #[test]
+    fn test_transform_overlapping_ranges() {
+        let op_a = delete_range(1, 4, 1);
+        let op_b = delete_range(3, 4, 2);
+        assert_eq!(OT::transform(&op_a, &op_b), vec![delete_range(1, 2, 1)]);
+        assert_eq!(OT::transform(&op_b, &op_a), vec![delete_range(1, 2, 2)]);
+        assert_eq!(assert_converges("abcdefgh", &op_a, &op_b), "ah");
+
+        // A range fully covered by the other vanishes
+        let inner = delete_range(2, 2, 1);
+        assert_eq!(
+            OT::transform(&inner, &op_a),
+            vec![Operation::Noop {
+                id: ElementId::new(1, 1)
+            }]
+        );
+        assert_eq!(assert_converges("abcdefgh", &inner, &op_a), "afgh");
+    }
+
+    #[test]
+    fn test_transform_keeps_range_ids_aligned() {
+        let ids: Vec<ElementId> = (1..=5).map(|c| ElementId::new(9, c)).collect();
+        let op_a = Operation::DeleteRange {
+            index: 0,
+            len: 5,
+            ids: ids.clone(),
+            stamp: ElementId::new(1, 9),
+        };
+        let op_b = delete_range(1, 2, 2);
+        match OT::transform(&op_a, &op_b).as_slice() {
+            [Operation::DeleteRange {
+                index,
+                len,
+                ids: rest,
+                ..
+            }] => {
+                assert_eq!((*index, *len), (0, 3));
+                assert_eq!(rest, &vec![ids[0], ids[3], ids[4]]);
+            }
+            other => panic!("Unexpected transform result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_transform_text_against_ranges_converges() {
+        let doc = "0123456789";
+        let ops = [
+            insert_text(0, "ab", 1),
+            insert_text(4, "cd", 2),
+            insert_text(10, "ef", 3),
+            delete_range(0, 3, 4),
+            delete_range(2, 5, 5),
+            delete_range(6, 4, 6),
+        ];
+        for op_a in &ops {
+            for op_b in &ops {
+                if op_a.id() != op_b.id() {
+                    assert_converges(doc, op_a, op_b);
+                }
+            }
+        }
+    }
+
+    /// Small deterministic generator so randomised tests are reproducible
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self, bound: usize) -> usize {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((self.0 >> 33) as usize) % bound.max(1)
+        }
+
+        fn text(&mut self, len: usize) -> String {
+            (0..len)
+                .map(|_| char::from(b'a' + self.next(26) as u8))
+                .collect()
+        }
+
+        fn text_operation(&mut self, doc: &str) -> TextOperation {
+            let mut op = TextOperation::new();
+            let mut remaining = doc.chars().count();
+            while remaining > 0 {
+                let n = 1 + self.next(remaining.min(5));
+                op = match self.next(3) {
+                    0 => op.retain(n),
+                    1 => op.delete(n),
+                    _ => op.insert(&self.text(n)).retain(n),
+                };
+                remaining -= n;
+            }
+            if self.next(2) == 0 {
+                op = op.insert(&self.text(3));
+            }
+            op
+        }
+    }
+
+    #[test]
+    fn test_text_operation_apply() {
+        let op = TextOperation::new()
+            .retain(6)
+            .delete(5)
+            .insert("there")
+            .retain(1);
+        assert_eq!(op.base_len(), 12);
+        assert_eq!(op.target_len(), 12);
+        assert_eq!(op.apply("hello world!").unwrap(), "hello there!");
+        // Inserts are kept ahead of deletes at the same position
+        assert_eq!(
+            op.components()[1..3],
+            [Component::Insert("there".into()), Component::Delete(5)]
+        );
+        assert!(matches!(
+            op.apply("too short"),
+            Err(CollaboriError::LengthMismatch {
+                expected: 12,
+                actual: 9
+            })
+        ));
+    }
+
+    #[test]
+    fn test_text_operation_serializes_like_ot_js() {
+        let op = TextOperation::new().retain(2).insert("hé").delete(3);
+        let json = serde_json::to_string(&op).unwrap();
+        assert_eq!(json, r#"[2,"hé",-3]"#);
+        let restored: TextOperation = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, op);
+
+        // Lengths that overflow are rejected instead of wrapping around
+        let max = i64::MAX;
+        for json in [
+            format!("[{max},{max},{max}]"),
+            format!("[-{max},-{max},-{max}]"),
+        ] {
+            assert!(serde_json::from_str::<TextOperation>(&json).is_err());
+        }
+        let long = TextOperation::new().retain(usize::MAX);
+        assert!(matches!(
+            long.clone().try_retain(1),
+            Err(CollaboriError::LengthOverflow)
+        ));
+        assert!(long.clone().try_delete(1).is_err());
+        assert!(long.try_insert("a").is_err());
+    }
+
+    #[test]
+    fn test_text_operation_from_operation() {
+        let op = insert_text(2, "xy", 1);
+        let text_op = TextOperation::from_operation(&op, 4);
+        assert_eq!(text_op.apply("abcd").unwrap(), "abxycd");
+        let op = delete_range(1, 2, 1);
+        let text_op = TextOperation::from_operation(&op, 4);
+        assert_eq!(text_op.apply("abcd").unwrap(), "ad");
+    }
+
+    #[test]
+    fn test_text_operation_compose_and_invert() {
+        let mut rng = Lcg(1);
+        for _ in 0..200 {
+            let len = rng.next(20);
+            let doc = rng.text(len);
+            let a = rng.text_operation(&doc);
+            let after_a = a.apply(&doc).unwrap();
+            let b = rng.text_operation(&after_a);
+            let after_b = b.apply(&after_a).unwrap();
+
+            let ab = TextOperation::compose(&a, &b).unwrap();
+            assert_eq!(ab.apply(&doc).unwrap(), after_b);
+            assert_eq!(a.invert(&doc).apply(&after_a).unwrap(), doc);
+        }
+    }
+
+    #[test]
+    fn test_text_operation_transform_converges() {
+        let mut rng = Lcg(2);
+        for _ in 0..200 {
+            let len = rng.next(20);
+            let doc = rng.text(len);
+            let a = rng.text_operation(&doc);
+            let b = rng.text_operation(&doc);
+            let (a_prime, b_prime) = TextOperation::transform(&a, &b).unwrap();
+
+            let left = b_prime.apply(&a.apply(&doc).unwrap()).unwrap();
+            let right = a_prime.apply(&b.apply(&doc).unwrap()).unwrap();
+            assert_eq!(left, right);
+        }
+
+        // Concurrent inserts at the same spot put a's text first
+        let a = TextOperation::new().retain(1).insert("A").retain(1);
+        let b = TextOperation::new().retain(1).insert("B").retain(1);
+        let (a_prime, _) = TextOperation::transform(&a, &b).unwrap();
+        assert_eq!(a_prime.apply("xBy").unwrap(), "xABy");
+    }
+
+    #[test]
+    fn test_ot_document_transforms_stale_operations() {
+        let mut doc = OTDocument::new("abc");
+        let first = TextOperation::new().retain(3).insert("d");
+        doc.receive(0, first).unwrap();
+
+        // Based on revision 0, so it has to move past nothing but still
+        // account for the appended 'd'
+        let stale = TextOperation::new().insert("X").retain(3);
+        let applied = doc.receive(0, stale).unwrap();
+        assert_eq!(applied, TextOperation::new().insert("X").retain(4));
+        assert_eq!(doc.text(), "Xabcd");
+        assert_eq!(doc.revision(), 2);
+        assert_eq!(doc.operations_since(1).unwrap().len(), 1);
+
+        assert!(matches!(
+            doc.receive(5, TextOperation::new().retain(5)),
+            Err(CollaboriError::UnknownRevision {
+                revision: 5,
+                current: 2
+            })
+        ));
+        assert!(doc.receive(2, TextOperation::new().retain(1)).is_err());
+        assert_eq!(doc.revision(), 2);
     }
 }
@@ -0,0 +1,581 @@
+use crate::codec::{decode_tag, from_bytes, malformed, to_bytes, Decode, Encode};
+use crate::crdt::RGA;
+use crate::data::{Awareness, ElementId, Operation};
+use crate::errors::CollaboriError;
+use crate::ot::TextOperation;
+use serde::{Deserialize, Serialize};
+use tokio::time::{Duration, Instant, Interval};
+use tokio_tungstenite::tungstenite::Message;
+
+/// The newest protocol version this build speaks
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The oldest protocol version this build still accepts
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// How many heartbeats in a row a peer may leave unanswered before its
+/// connection is considered dead
+pub const DEFAULT_MAX_MISSED_PINGS: u32 = 3;
+
+/// Every message exchanged between `SyncManager` and its clients.
+///
+/// A connection opens with a `Hello` from the client announcing the versions
+/// it speaks, answered by a `Hello` from the server carrying the version both
+/// sides use from then on, or by an `Error` if they share none.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Envelope {
+    /// Version negotiation: the client speaks `min_version..=version`; the
+    /// server answers with the chosen version in both fields
+    Hello {
+        version: u32,
+        min_version: u32,
+    },
+    /// Moves the connection to a document's room. `revision` is the last OT
+    /// revision the client has seen, if it is resuming
+    Join {
+        document: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        revision: Option<usize>,
+    },
+    /// An edit sent to the room, or relayed from another client
+    Op {
+        edit: Edit,
+    },
+    /// Confirms the client's own edit: `id` is `Operation::last_id` for CRDT
+    /// operations, `revision` the revision a text operation became
+    Ack {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<ElementId>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        revision: Option<usize>,
+    },
+    /// The state of a document, sent when a client joins its room
+    Snapshot {
+        document: String,
+        state: DocumentSnapshot,
+    },
+    /// A client's awareness state, relayed to the rest of its room but never
+    /// stored. Clients send their own, `None` to clear it; the server relays
+    /// it tagged with the sending `client`, and with `None` once that client
+    /// leaves or goes quiet for longer than the presence timeout
+    Presence {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client: Option<u64>,
+        state: Option<Awareness>,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+    /// A heartbeat, answered by a `Pong` with the same nonce. Clients may
+    /// send them; the server pings with WebSocket ping frames instead, which
+    /// every client answers on its own
+    Ping {
+        nonce: u64,
+    },
+    Pong {
+        nonce: u64,
+    },
+}
+
+/// An edit in whichever form the server's `SyncMode` expects
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Edit {
+    Crdt(Operation),
+    /// A text operation applying to the document at `revision`
+    Text {
+        revision: usize,
+        operation: TextOperation,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum DocumentSnapshot {
+    /// The room's replica; merge it into the local one
+    Crdt(Box<RGA>),
+    Text {
+        revision: usize,
+        text: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The peers share no protocol version; the connection is closed
+    UnsupportedVersion,
+    /// The message could not be decoded or was not expected at this point
+    InvalidMessage,
+    /// The edit could not be applied, e.g. its revision is unknown
+    RejectedOperation,
+    /// The requested document could not be opened
+    DocumentUnavailable,
+    /// The user's role in the document doesn't allow the request, e.g. a
+    /// viewer sending an edit
+    PermissionDenied,
+    /// The edit was applied but could not be stored, so it isn't
+    /// acknowledged
+    StorageFailed,
+}
+
+impl Envelope {
+    /// The `Hello` a client opens with
+    pub fn hello() -> Self {
+        Envelope::Hello {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+        }
+    }
+
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        Envelope::Error {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// How envelopes travel over a connection: JSON in text frames, or the
+/// compact encoding of `codec` in binary frames. The server answers in the
+/// format the client's `Hello` arrived in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    Json,
+    Binary,
+}
+
+impl Codec {
+    /// The codec a frame is written in, if it carries an envelope at all
+    pub fn of(message: &Message) -> Option<Codec> {
+        match message {
+            Message::Text(_) => Some(Codec::Json),
+            Message::Binary(_) => Some(Codec::Binary),
+            _ => None,
+        }
+    }
+
+    pub fn encode(self, envelope: &Envelope) -> Message {
+        match self {
+            Codec::Json => Message::Text(serde_json::to_string(envelope).unwrap().into()),
+            Codec::Binary => Message::Binary(to_bytes(envelope).into()),
+        }
+    }
+
+    /// Decodes a text or binary frame; other frames are not envelopes
+    pub fn decode(message: &Message) -> Option<Result<Envelope, CollaboriError>> {
+        match message {
+            Message::Text(text) => Some(serde_json::from_str(text).map_err(Into::into)),
+            Message::Binary(bytes) => Some(from_bytes(bytes)),
+            _ => None,
+        }
+    }
+}
+
+impl Encode for Envelope {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Envelope::Hello {
+                version,
+                min_version,
+            } => {
+                out.push(0);
+                version.encode(out);
+                min_version.encode(out);
+            }
+            Envelope::Join { document, revision } => {
+                out.push(1);
+                document.encode(out);
+                revision.encode(out);
+            }
+            Envelope::Op { edit } => {
+                out.push(2);
+                edit.encode(out);
+            }
+            Envelope::Ack { id, revision } => {
+                out.push(3);
+                id.encode(out);
+                revision.encode(out);
+            }
+            Envelope::Snapshot { document, state } => {
+                out.push(4);
+                document.encode(out);
+                state.encode(out);
+            }
+            Envelope::Presence { client, state } => {
+                out.push(5);
+                client.encode(out);
+                state.encode(out);
+            }
+            Envelope::Error { code, message } => {
+                out.push(6);
+                code.encode(out);
+                message.encode(out);
+            }
+            Envelope::Ping { nonce } => {
+                out.push(7);
+                nonce.encode(out);
+            }
+            Envelope::Pong { nonce } => {
+                out.push(8);
+                nonce.encode(out);
+            }
+        }
+    }
+}
+
+impl Decode for Envelope {
+    fn decode(input: &mut &[u8]) -> Result<Self, CollaboriError> {
+        Ok(match decode_tag(input)? {
+            0 => Envelope::Hello {
+                version: u32::decode(input)?,
+                min_version: u32::decode(input)?,
+            },
+            1 => Envelope::Join {
+                document: String::decode(input)?,
+                revision: Option::decode(input)?,
+            },
+            2 => Envelope::Op {
+                edit: Edit::decode(input)?,
+            },
+            3 => Envelope::Ack {
+                id: Option::decode(input)?,
+                revision: Option::decode(input)?,
+            },
+            4 => Envelope::Snapshot {
+                document: String::decode(input)?,
+                state: DocumentSnapshot::decode(input)?,
+            },
+            5 => Envelope::Presence {
+                client: Option::decode(input)?,
+                state: Option::decode(input)?,
+            },
+            6 => Envelope::Error {
+                code: ErrorCode::decode(input)?,
+                message: String::decode(input)?,
+            },
+            7 => Envelope::Ping {
+                nonce: u64::decode(input)?,
+            },
+            8 => Envelope::Pong {
+                nonce: u64::decode(input)?,
+            },
+            _ => return Err(malformed("unknown envelope")),
+        })
+    }
+}
+
+impl Encode for Edit {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Edit::Crdt(op) => {
+                out.push(0);
+                op.encode(out);
+            }
+            Edit::Text {
+                revision,
+                operation,
+            } => {
+                out.push(1);
+                revision.encode(out);
+                operation.encode(out);
+            }
+        }
+    }
+}
+
+impl Decode for Edit {
+    fn decode(input: &mut &[u8]) -> Result<Self, CollaboriError> {
+        match decode_tag(input)? {
+            0 => Ok(Edit::Crdt(Operation::decode(input)?)),
+            1 => Ok(Edit::Text {
+                revision: usize::decode(input)?,
+                operation: TextOperation::decode(input)?,
+            }),
+            _ => Err(malformed("unknown edit")),
+        }
+    }
+}
+
+impl Encode for DocumentSnapshot {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            DocumentSnapshot::Crdt(rga) => {
+                out.push(0);
+                rga.encode(out);
+            }
+            DocumentSnapshot::Text { revision, text } => {
+                out.push(1);
+                revision.encode(out);
+                text.encode(out);
+            }
+        }
+    }
+}
+
+impl Decode for DocumentSnapshot {
+    fn decode(input: &mut &[u8]) -> Result<Self, CollaboriError> {
+        match decode_tag(input)? {
+            0 => Ok(DocumentSnapshot::Crdt(Box::new(RGA::decode(input)?))),
+            1 => Ok(DocumentSnapshot::Text {
+                revision: usize::decode(input)?,
+                text: String::decode(input)?,
+            }),
+            _ => Err(malformed("unknown snapshot")),
+        }
+    }
+}
+
+impl Encode for ErrorCode {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(match self {
+            ErrorCode::UnsupportedVersion => 0,
+            ErrorCode::InvalidMessage => 1,
+            ErrorCode::RejectedOperation => 2,
+            ErrorCode::DocumentUnavailable => 3,
+            ErrorCode::PermissionDenied => 4,
+            ErrorCode::StorageFailed => 5,
+        });
+    }
+}
+
+impl Decode for ErrorCode {
+    fn decode(input: &mut &[u8]) -> Result<Self, CollaboriError> {
+        match decode_tag(input)? {
+            0 => Ok(ErrorCode::UnsupportedVersion),
+            1 => Ok(ErrorCode::InvalidMessage),
+            2 => Ok(ErrorCode::RejectedOperation),
+            3 => Ok(ErrorCode::DocumentUnavailable),
+            4 => Ok(ErrorCode::PermissionDenied),
+            5 => Ok(ErrorCode::StorageFailed),
+            _ => Err(malformed("unknown error code")),
+        }
+    }
+}
+
+/// Picks the version to speak with a peer supporting
+/// `min_version..=version`, if there is one both sides accept
+pub fn negotiate(version: u32, min_version: u32) -> Option<u32> {
+    let chosen = version.min(PROTOCOL_VERSION);
+    (chosen >= min_version && chosen >= MIN_PROTOCOL_VERSION).then_some(chosen)
+}
+
+/// The pings one side of a connection sends and the pongs answering them
+#[derive(Debug)]
+pub(crate) struct Heartbeat {
+    interval: Option<Interval>,
+    max_missed: u32,
+    missed: u32,
+    nonce: u64,
+    /// The unanswered ping and when it was sent
+    awaiting: Option<(u64, Instant)>,
+    /// Whether anything arrived from the peer since the last ping
+    heard: bool,
+}
+
+impl Heartbeat {
+    /// Pings every `period`, or never without one
+    pub(crate) fn new(period: Option<Duration>, max_missed: u32) -> Self {
+        Heartbeat {
+            interval: period
+                .filter(|period| !period.is_zero())
+                .map(|period| tokio::time::interval_at(Instant::now() + period, period)),
+            max_missed: max_missed.max(1),
+            missed: 0,
+            nonce: 0,
+            awaiting: None,
+            heard: false,
+        }
+    }
+
+    /// Waits until the next ping is due and returns its nonce, or `None`
+    /// once the peer has missed too many
+    pub(crate) async fn tick(&mut self) -> Option<u64> {
+        match &mut self.interval {
+            Some(interval) => interval.tick().await,
+            None => std::future::pending().await,
+        };
+        let heard = std::mem::take(&mut self.heard);
+        if self.awaiting.is_some() && !heard {
+            self.missed += 1;
+            if self.missed >= self.max_missed {
+                return None;
+            }
+        }
+        self.nonce += 1;
+        self.awaiting = Some((self.nonce, Instant::now()));
+        Some(self.nonce)
+    }
+
+    /// Records a pong, returning the round trip if it answers the latest ping
+    pub(crate) fn pong(&mut self, nonce: u64) -> Option<Duration> {
+        self.missed = 0;
+        match self.awaiting {
+            Some((awaited, sent)) if awaited == nonce => {
+                self.awaiting = None;
+                Some(sent.elapsed())
+            }
+            _ => None,
+        }
+    }
+
+    /// Records that something arrived from the peer, which proves it alive
+    /// whether or not it answers the pings
+    pub(crate) fn heard(&mut self) {
+        self.missed = 0;
+        self.heard = true;
+    }
+
+    pub(crate) fn missed(&self) -> u32 {
+        self.missed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_picks_highest_shared_version() {
+        assert_eq!(
+            negotiate(PROTOCOL_VERSION + 3, MIN_PROTOCOL_VERSION),
+            Some(PROTOCOL_VERSION)
+        );
+        assert_eq!(negotiate(PROTOCOL_VERSION, 0), Some(PROTOCOL_VERSION));
+        assert_eq!(negotiate(PROTOCOL_VERSION + 3, PROTOCOL_VERSION + 1), None);
+        assert_eq!(negotiate(MIN_PROTOCOL_VERSION - 1, 0), None);
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_counts_missed_pings() {
+        let mut heartbeat = Heartbeat::new(Some(Duration::from_millis(10)), 2);
+        assert_eq!(heartbeat.tick().await, Some(1));
+        assert!(heartbeat.pong(1).is_some());
+        assert!(heartbeat.pong(1).is_none());
+
+        // A late pong keeps the connection alive without measuring anything
+        assert_eq!(heartbeat.tick().await, Some(2));
+        assert_eq!(heartbeat.tick().await, Some(3));
+        assert_eq!(heartbeat.missed(), 1);
+        assert!(heartbeat.pong(2).is_none());
+        assert_eq!(heartbeat.missed(), 0);
+
+        assert_eq!(heartbeat.tick().await, Some(4));
+        assert_eq!(heartbeat.tick().await, None);
+
+        // Any message from the peer counts, answered ping or not
+        let mut heartbeat = Heartbeat::new(Some(Duration::from_millis(10)), 1);
+        assert_eq!(heartbeat.tick().await, Some(1));
+        heartbeat.heard();
+        assert_eq!(heartbeat.tick().await, Some(2));
+        assert_eq!(heartbeat.tick().await, None);
+
+        // Without a period there is nothing to wait for
+        let mut disabled = Heartbeat::new(None, 2);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), disabled.tick())
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_envelope_wire_format() {
+        let op = Envelope::Op {
+            edit: Edit::Text {
+                revision: 2,
+                operation: TextOperation::new().retain(1).insert("a"),
+            },
+        };
+        assert_eq!(
+            serde_json::to_string(&op).unwrap(),
+            r#"{"type":"op","edit":{"text":{"revision":2,"operation":[1,"a"]}}}"#
+        );
+        let ack = serde_json::to_string(&Envelope::Ack {
+            id: None,
+            revision: Some(3),
+        })
+        .unwrap();
+        assert_eq!(ack, r#"{"type":"ack","revision":3}"#);
+
+        // Snapshots of replicas survive the tagged envelope
+        let mut rga = RGA::new();
+        rga.insert_text(0, "hey");
+        rga.delete(1);
+        let json = serde_json::to_string(&Envelope::Snapshot {
+            document: "notes".to_string(),
+            state: DocumentSnapshot::Crdt(Box::new(rga)),
+        })
+        .unwrap();
+        match serde_json::from_str(&json).unwrap() {
+            Envelope::Snapshot {
+                state: DocumentSnapshot::Crdt(rga),
+                ..
+            } => assert_eq!(rga.text(), "hy"),
+            other => panic!("Unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_range_deletions_carry_an_id_per_character() {
+        let mut rga = RGA::new();
+        rga.insert_text(0, "hello");
+        let envelope = Envelope::Op {
+            edit: Edit::Crdt(rga.delete_range(1, 3)),
+        };
+        let mut json = serde_json::to_value(&envelope).unwrap();
+        assert!(serde_json::from_value::<Envelope>(json.clone()).is_ok());
+
+        for len in [0, 2, 4] {
+            json["edit"]["crdt"]["DeleteRange"]["len"] = len.into();
+            let err = serde_json::from_value::<Envelope>(json.clone()).unwrap_err();
+            assert!(err.to_string().contains("carries 3 ids"));
+        }
+    }
+
+    #[test]
+    fn test_binary_envelopes_are_compact() {
+        let mut rga = RGA::new();
+        rga.insert_text(0, &"lorem ipsum dolor sit amet ".repeat(40));
+        rga.delete(3);
+        let snapshot = Envelope::Snapshot {
+            document: "notes".to_string(),
+            state: DocumentSnapshot::Crdt(Box::new(rga.clone())),
+        };
+
+        let json = Codec::Json.encode(&snapshot);
+        let binary = Codec::Binary.encode(&snapshot);
+        assert_eq!(Codec::of(&binary), Some(Codec::Binary));
+        assert!(binary.len() * 20 < json.len());
+
+        match Codec::decode(&binary).unwrap().unwrap() {
+            Envelope::Snapshot {
+                document,
+                state: DocumentSnapshot::Crdt(decoded),
+            } => {
+                assert_eq!(document, "notes");
+                assert_eq!(decoded.text(), rga.text());
+                assert_eq!(
+                    serde_json::to_value(&*decoded).unwrap(),
+                    serde_json::to_value(&rga).unwrap()
+                );
+            }
+            other => panic!("Unexpected message {:?}", other),
+        }
+
+        let error = Codec::Binary.encode(&Envelope::error(ErrorCode::RejectedOperation, "no"));
+        assert!(matches!(
+            Codec::decode(&error).unwrap().unwrap(),
+            Envelope::Error {
+                code: ErrorCode::RejectedOperation,
+                ..
+            }
+        ));
+        assert!(Codec::decode(&Message::Binary(vec![9].into()))
+            .unwrap()
+            .is_err());
+    }
+}
@@ -0,0 +1,392 @@
+use crate::codec::{from_bytes, to_bytes};
+use crate::crdt::RGA;
+use crate::data::Operation;
+use crate::errors::CollaboriError;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+
+/// Durable storage for the documents a sync server edits
+pub trait DocumentStore: Debug + Send + Sync {
+    /// Records an operation the server accepted for `document`
+    fn append(&self, document: &str, op: &Operation) -> Result<(), CollaboriError>;
+
+    /// Records several operations for `document` at once, letting stores
+    /// make them durable together
+    fn append_all(&self, document: &str, ops: &[Operation]) -> Result<(), CollaboriError> {
+        ops.iter().try_for_each(|op| self.append(document, op))
+    }
+
+    /// Stores the full state of `document`, making the operations recorded so
+    /// far redundant
+    fn snapshot(&self, document: &str, rga: &RGA) -> Result<(), CollaboriError>;
+
+    /// Rebuilds `document` from its latest snapshot and the operations
+    /// recorded since, or returns `None` if nothing was stored
+    fn load(&self, document: &str) -> Result<Option<RGA>, CollaboriError>;
+
+    /// Prepares the store for a server starting to use it
+    fn init(&self) -> Result<(), CollaboriError> {
+        Ok(())
+    }
+}
+
+/// Stores each document in a directory as a binary snapshot of its `RGA`
+/// (`<document>.snapshot`, see [`crate::codec`]) and an append-only log of the
+/// operations since, one JSON record per line (`<document>.log`).
+///
+/// A record is only complete once its newline is written, so a crash halfway
+/// through an append leaves a partial final record, which `load` discards.
+#[derive(Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    /// Opens a store in `dir`, creating the directory if needed
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, CollaboriError> {
+        let store = Self::new(dir);
+        store.init()?;
+        Ok(store)
+    }
+
+    /// Describes a store in `dir` without touching the filesystem; the
+    /// directory is created when a server starts with the store
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        FileStore {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    fn path(&self, document: &str, extension: &str) -> PathBuf {
+        self.dir
+            .join(format!("{}.{}", file_stem(document), extension))
+    }
+}
+
+/// Escapes a document id into a file name that stays inside the store's
+/// directory
+fn file_stem(document: &str) -> String {
+    let mut stem = String::new();
+    for byte in document.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+            stem.push(byte as char);
+        } else {
+            stem.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    stem
+}
+
+impl DocumentStore for FileStore {
+    fn append(&self, document: &str, op: &Operation) -> Result<(), CollaboriError> {
+        self.append_all(document, std::slice::from_ref(op))
+    }
+
+    fn append_all(&self, document: &str, ops: &[Operation]) -> Result<(), CollaboriError> {
+        let mut records = Vec::new();
+        for op in ops {
+            serde_json::to_writer(&mut records, op)?;
+            records.push(b'\n');
+        }
+        let mut log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(document, "log"))?;
+        log.write_all(&records)?;
+        log.sync_data()?;
+        Ok(())
+    }
+
+    fn snapshot(&self, document: &str, rga: &RGA) -> Result<(), CollaboriError> {
+        // Write aside and rename so a crash never leaves a torn snapshot. A
+        // crash before the log is cleared only means replaying operations
+        // the snapshot already holds, which RGA::apply ignores.
+        let tmp = self.path(document, "snapshot.tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&to_bytes(rga))?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.path(document, "snapshot"))?;
+        File::create(self.path(document, "log"))?.sync_all()?;
+        Ok(())
+    }
+
+    fn load(&self, document: &str) -> Result<Option<RGA>, CollaboriError> {
+        let snapshot = match fs::read(self.path(document, "snapshot")) {
+            Ok(bytes) => Some(from_bytes::<RGA>(&bytes)?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+        let log_path = self.path(document, "log");
+        let log = match fs::read(&log_path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(snapshot),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut rga = snapshot.unwrap_or_default();
+        let mut complete = 0;
+        let mut records = log.split_inclusive(|&b| b == b'\n').peekable();
+        while let Some(record) = records.next() {
+            let last = records.peek().is_none();
+            if !record.ends_with(b"\n") {
+                break;
+            }
+            match serde_json::from_slice::<Operation>(record) {
+                Ok(op) => rga.apply(&op),
+                // A torn final record can still end in a newline if the
+                // filesystem reordered the write
+                Err(_) if last => break,
+                Err(err) => return Err(err.into()),
+            }
+            complete += record.len();
+        }
+        if complete < log.len() {
+            OpenOptions::new()
+                .write(true)
+                .open(&log_path)?
+                .set_len(complete as u64)?;
+        }
+        Ok(Some(rga))
+    }
+
+    fn init(&self) -> Result<(), CollaboriError> {
+        fs::create_dir_all(&self.dir)?;
+        Ok(())
+    }
+}
+
+/// Tells whoever appended an operation whether it was stored, or the store's
+/// error
+pub(crate) type Appended = oneshot::Receiver<Result<(), String>>;
+
+/// Work queued for a [`StoreWriter`]
+enum Job {
+    Append {
+        document: String,
+        op: Operation,
+        done: oneshot::Sender<Result<(), String>>,
+    },
+    Snapshot {
+        document: String,
+        rga: Box<RGA>,
+    },
+    Load {
+        document: String,
+        reply: oneshot::Sender<Result<Option<RGA>, CollaboriError>>,
+    },
+    Flush(oneshot::Sender<()>),
+}
+
+/// Runs a store's I/O on a blocking thread, so the sync server never waits
+/// on the disk while holding a room's lock.
+///
+/// Appends report once the operation is durable, or the store's error.
+///
+/// Jobs run in the order they were queued, so a document loaded after its
+/// room closed sees the room's last snapshot. Operations appended while the
+/// previous jobs ran are written with one sync per document.
+#[derive(Debug, Clone)]
+pub(crate) struct StoreWriter {
+    jobs: mpsc::UnboundedSender<Job>,
+}
+
+impl StoreWriter {
+    /// Starts the thread, which stops once every handle is dropped
+    pub(crate) fn spawn(store: Arc<dyn DocumentStore>) -> Self {
+        let (jobs, mut queue) = mpsc::unbounded_channel();
+        tokio::task::spawn_blocking(move || {
+            while let Some(job) = queue.blocking_recv() {
+                let mut batch = vec![job];
+                while let Ok(job) = queue.try_recv() {
+                    batch.push(job);
+                }
+                run_batch(store.as_ref(), batch);
+            }
+        });
+        StoreWriter { jobs }
+    }
+
+    /// Queues an operation to be recorded for `document`, returning a
+    /// receiver told when the store has it
+    pub(crate) fn append(&self, document: &str, op: Operation) -> Appended {
+        let (done, appended) = oneshot::channel();
+        let job = Job::Append {
+            document: document.to_string(),
+            op,
+            done,
+        };
+        if let Err(mpsc::error::SendError(Job::Append { done, .. })) = self.jobs.send(job) {
+            let _ = done.send(Err("store writer stopped".to_string()));
+        }
+        appended
+    }
+
+    /// Queues a snapshot of `document`
+    pub(crate) fn snapshot(&self, document: &str, rga: RGA) {
+        let _ = self.jobs.send(Job::Snapshot {
+            document: document.to_string(),
+            rga: Box::new(rga),
+        });
+    }
+
+    /// Loads `document` once the jobs queued before have run
+    pub(crate) async fn load(&self, document: &str) -> Result<Option<RGA>, CollaboriError> {
+        let (reply, loaded) = oneshot::channel();
+        self.jobs
+            .send(Job::Load {
+                document: document.to_string(),
+                reply,
+            })
+            .map_err(|_| CollaboriError::ChannelClosed("store writer"))?;
+        loaded
+            .await
+            .map_err(|_| CollaboriError::ChannelClosed("store writer"))?
+    }
+
+    /// Waits until the jobs queued so far have run
+    pub(crate) async fn flush(&self) {
+        let (done, flushed) = oneshot::channel();
+        if self.jobs.send(Job::Flush(done)).is_ok() {
+            let _ = flushed.await;
+        }
+    }
+}
+
+/// Runs a batch of jobs in order, gathering consecutive appends by document
+fn run_batch(store: &dyn DocumentStore, batch: Vec<Job>) {
+    let mut appends: HashMap<String, Appends> = HashMap::new();
+    for job in batch {
+        match job {
+            Job::Append { document, op, done } => {
+                let (ops, waiting) = appends.entry(document).or_default();
+                ops.push(op);
+                waiting.push(done);
+                continue;
+            }
+            Job::Snapshot { document, rga } => {
+                write_appends(store, &mut appends);
+                if let Err(err) = store.snapshot(&document, &rga) {
+                    println!("Failed to store snapshot: {}", err);
+                }
+            }
+            Job::Load { document, reply } => {
+                write_appends(store, &mut appends);
+                let _ = reply.send(store.load(&document));
+            }
+            Job::Flush(done) => {
+                write_appends(store, &mut appends);
+                let _ = done.send(());
+            }
+        }
+    }
+    write_appends(store, &mut appends);
+}
+
+/// A document's pending operations and whoever waits on each
+type Appends = (Vec<Operation>, Vec<oneshot::Sender<Result<(), String>>>);
+
+fn write_appends(store: &dyn DocumentStore, appends: &mut HashMap<String, Appends>) {
+    for (document, (ops, waiting)) in appends.drain() {
+        let result = store.append_all(&document, &ops).map_err(|err| {
+            println!("Failed to store operations: {}", err);
+            err.to_string()
+        });
+        for done in waiting {
+            let _ = done.send(result.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::generate_unique_id;
+
+    fn temp_store() -> FileStore {
+        FileStore::open(std::env::temp_dir().join(generate_unique_id())).unwrap()
+    }
+
+    #[test]
+    fn test_file_store_replays_log_over_snapshot() {
+        let store = temp_store();
+        assert!(store.load("notes").unwrap().is_none());
+
+        let mut rga = RGA::new();
+        store.append("notes", &rga.insert_text(0, "hello")).unwrap();
+        assert_eq!(store.load("notes").unwrap().unwrap().text(), "hello");
+
+        store.snapshot("notes", &rga).unwrap();
+        assert_eq!(fs::read(store.path("notes", "log")).unwrap().len(), 0);
+        store.append("notes", &rga.delete_range(0, 1)).unwrap();
+        store.append("notes", &rga.insert(4, '!')).unwrap();
+        assert_eq!(store.load("notes").unwrap().unwrap().text(), "ello!");
+
+        // Documents are kept apart, whatever their ids contain
+        assert!(store.load("../notes").unwrap().is_none());
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_store_discards_partial_final_record() {
+        let store = temp_store();
+        let mut rga = RGA::new();
+        store.append("notes", &rga.insert_text(0, "abc")).unwrap();
+        let complete = fs::read(store.path("notes", "log")).unwrap().len();
+
+        // Simulate a crash halfway through the next append
+        let record = serde_json::to_vec(&rga.insert(3, 'd')).unwrap();
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(store.path("notes", "log"))
+            .unwrap();
+        log.write_all(&record[..record.len() / 2]).unwrap();
+
+        assert_eq!(store.load("notes").unwrap().unwrap().text(), "abc");
+        let log_len = fs::read(store.path("notes", "log")).unwrap().len();
+        assert_eq!(log_len, complete);
+
+        // Appending after recovery starts from a clean record boundary
+        store.append("notes", &rga.insert(3, 'e')).unwrap();
+        let restored = store.load("notes").unwrap().unwrap();
+        assert_eq!(restored.text(), "abce");
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_store_writer_runs_jobs_in_order() {
+        let store = Arc::new(temp_store());
+        let writer = StoreWriter::spawn(store.clone());
+        let mut rga = RGA::new();
+        writer.append("notes", rga.insert_text(0, "hello"));
+        writer.snapshot("notes", rga.clone());
+        for op in [rga.delete(0), rga.insert(4, '!')] {
+            writer.append("notes", op);
+        }
+        assert_eq!(writer.load("notes").await.unwrap().unwrap().text(), "ello!");
+
+        writer.append("notes", rga.delete(0));
+        writer.flush().await;
+        assert_eq!(store.load("notes").unwrap().unwrap().text(), "llo!");
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_store_writer_reports_appends() {
+        let store = Arc::new(temp_store());
+        let writer = StoreWriter::spawn(store.clone());
+        let mut rga = RGA::new();
+        let appended = writer.append("notes", rga.insert_text(0, "hi"));
+        assert_eq!(appended.await.unwrap(), Ok(()));
+        assert_eq!(store.load("notes").unwrap().unwrap().text(), "hi");
+
+        // Failures reach whoever waits on the operation
+        fs::remove_dir_all(&store.dir).unwrap();
+        let appended = writer.append("notes", rga.insert(2, '!'));
+        assert!(appended.await.unwrap().is_err());
+    }
+}
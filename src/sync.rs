@@ -1,13 +1,494 @@
-use crate::data::Operation;
+use crate::auth::{Authenticator, Identity, Role};
+use crate::config::ServerConfig;
+use crate::crdt::RGA;
+use crate::data::{Awareness, ElementId, Operation, VersionVector};
+use crate::errors::CollaboriError;
+use crate::ot::{OTDocument, TextOperation};
+use crate::protocol::{negotiate, Codec, DocumentSnapshot, Edit, Envelope, ErrorCode, Heartbeat};
+use crate::store::{Appended, DocumentStore, StoreWriter};
+use futures_util::stream::SplitStream;
 use futures_util::{SinkExt, StreamExt};
-use tokio::net::TcpListener;
-use tokio::sync::{broadcast, mpsc};
-use tokio_tungstenite::accept_async;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{timeout, Duration, Instant};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::header::{AUTHORIZATION, ORIGIN};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, WebSocketConfig};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{accept_hdr_async_with_config, WebSocketStream};
+
+/// How the server treats the edits clients send
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncMode {
+    /// CRDT operations ([`Edit::Crdt`]) are applied to the room's [`RGA`]
+    /// and relayed to every client
+    #[default]
+    Crdt,
+    /// Text operations ([`Edit::Text`]) are transformed against a
+    /// server-side history and assigned revisions
+    Ot,
+}
+
+/// An update fanned out to every connection in a room
+#[derive(Debug, Clone)]
+enum Update {
+    /// A CRDT operation sent by connection `origin`
+    Operation { origin: u64, op: Operation },
+    /// Connection `origin`'s CRDT operation ending at `id` is stored, or was
+    /// applied to a room without a store
+    Stored { origin: u64, id: ElementId },
+    /// Connection `origin`'s CRDT operation was applied, but the store failed
+    /// to record it
+    StoreFailed { origin: u64, message: String },
+    /// A text operation sent by connection `origin` that produced `revision`
+    Revision {
+        origin: u64,
+        revision: usize,
+        operation: TextOperation,
+    },
+    /// Connection `origin`'s awareness state changed, `None` when it left
+    Presence {
+        origin: u64,
+        state: Option<Awareness>,
+    },
+}
+
+/// The room clients connecting without a document in the path start in
+pub const DEFAULT_DOCUMENT: &str = "default";
+
+/// How many operations a room logs before snapshotting its document
+pub const DEFAULT_SNAPSHOT_INTERVAL: usize = 1000;
+
+/// How long a client's awareness state lasts without being refreshed
+pub const DEFAULT_PRESENCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many updates a room buffers for connections that haven't sent them yet
+pub const DEFAULT_ROOM_CAPACITY: usize = 100;
+
+/// How many messages a connection queues while its socket is busy
+pub const DEFAULT_CONNECTION_CAPACITY: usize = 100;
+
+/// How often the server pings its clients to detect dead connections
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a connection may take to upgrade to WebSocket and say hello
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a shutting down server waits for its connections to close
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a closing connection waits for its queued messages to be sent
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What happens to a connection that falls more than the room's capacity
+/// behind, e.g. because its client reads slower than the room edits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LagPolicy {
+    /// Bring the client up to date again: CRDT clients get a fresh snapshot,
+    /// OT clients the revisions they missed
+    #[default]
+    Resync,
+    /// Close the connection with code 1013 (try again later)
+    Disconnect,
+}
 
 #[derive(Debug)]
 pub struct SyncManager {
-    pub broadcaster: broadcast::Sender<Operation>,
     shutdown: broadcast::Sender<()>,
+    rooms: Arc<Mutex<HashMap<String, Arc<Room>>>>,
+    config: ServerConfig,
+}
+
+/// The authoritative state of a room's document
+#[derive(Debug)]
+enum DocumentState {
+    /// `logged` counts the operations stored since the last snapshot, and
+    /// `stable` is what every client had seen at the last garbage collection
+    Crdt {
+        rga: Box<RGA>,
+        logged: usize,
+        stable: VersionVector,
+    },
+    /// `origins` holds the connection that produced each revision, so a
+    /// connection catching up gets acks for its own operations
+    Ot {
+        document: OTDocument,
+        origins: Vec<u64>,
+    },
+}
+
+/// A connection's feed of a room's updates
+#[derive(Debug)]
+struct Subscription {
+    updates: broadcast::Receiver<Update>,
+    /// Messages bringing the client up to date before the first update
+    catch_up: Vec<Envelope>,
+    /// The OT revision the catch-up leaves the client at
+    revision: Option<usize>,
+}
+
+/// The connections editing one document and the document's state
+#[derive(Debug)]
+struct Room {
+    document: String,
+    broadcaster: broadcast::Sender<Update>,
+    state: Mutex<DocumentState>,
+    /// Each connection's awareness state and when it last sent it
+    presence: Mutex<HashMap<u64, (Awareness, Instant)>>,
+    /// What each connection has been sent of a CRDT document
+    versions: Mutex<HashMap<u64, VersionVector>>,
+    /// Only changed while holding the room map's lock
+    clients: AtomicUsize,
+    store: Option<StoreWriter>,
+    snapshot_interval: usize,
+}
+
+impl Room {
+    /// Opens a room on a document, `rga` being what the store holds of it
+    fn open(shared: &Shared, document: &str, rga: Option<RGA>) -> Self {
+        let config = &shared.config;
+        let (tx, _) = broadcast::channel(config.room_capacity);
+        let state = match config.mode {
+            SyncMode::Crdt => DocumentState::Crdt {
+                rga: Box::new(rga.unwrap_or_default()),
+                logged: 0,
+                stable: VersionVector::new(),
+            },
+            SyncMode::Ot => DocumentState::Ot {
+                document: OTDocument::default(),
+                origins: Vec::new(),
+            },
+        };
+        Room {
+            document: document.to_string(),
+            broadcaster: tx,
+            state: Mutex::new(state),
+            presence: Mutex::new(HashMap::new()),
+            versions: Mutex::new(HashMap::new()),
+            clients: AtomicUsize::new(0),
+            store: shared.store.clone(),
+            snapshot_interval: config.snapshot_interval,
+        }
+    }
+
+    /// Subscribes connection `id` to the room's updates, along with the
+    /// messages that bring its client up to date: the OT operations since
+    /// `revision` when it is known, and a snapshot otherwise, followed by the
+    /// other clients' awareness states
+    fn subscribe(&self, id: u64, revision: Option<usize>) -> Subscription {
+        // Holding the locks lines the catch-up up with the updates
+        let state = self.state.lock().unwrap();
+        let presence = self.presence.lock().unwrap();
+        let snapshot = |state| Envelope::Snapshot {
+            document: self.document.clone(),
+            state,
+        };
+        let (mut catch_up, current) = match &*state {
+            DocumentState::Crdt { rga, .. } => {
+                let mut versions = self.versions.lock().unwrap();
+                versions.insert(id, rga.version().clone());
+                (vec![snapshot(DocumentSnapshot::Crdt(rga.clone()))], None)
+            }
+            DocumentState::Ot { document, origins } => {
+                let catch_up = match revision.and_then(|r| Some((r, document.operations_since(r)?)))
+                {
+                    Some((revision, missed)) => missed
+                        .iter()
+                        .enumerate()
+                        .map(|(i, operation)| match origins.get(revision + i) {
+                            Some(origin) if *origin == id => Envelope::Ack {
+                                id: None,
+                                revision: Some(revision + i + 1),
+                            },
+                            _ => Envelope::Op {
+                                edit: Edit::Text {
+                                    revision: revision + i,
+                                    operation: operation.clone(),
+                                },
+                            },
+                        })
+                        .collect(),
+                    None => vec![snapshot(DocumentSnapshot::Text {
+                        revision: document.revision(),
+                        text: document.text().to_string(),
+                    })],
+                };
+                (catch_up, Some(document.revision()))
+            }
+        };
+        catch_up.extend(
+            presence
+                .iter()
+                .map(|(client, (awareness, _))| Envelope::Presence {
+                    client: Some(*client),
+                    state: Some(awareness.clone()),
+                }),
+        );
+        Subscription {
+            updates: self.broadcaster.subscribe(),
+            catch_up,
+            revision: current,
+        }
+    }
+
+    /// Records that connection `id` was sent the operation ending at `last`
+    fn observe(&self, id: u64, last: ElementId) {
+        if let Some(version) = self.versions.lock().unwrap().get_mut(&id) {
+            version.observe(last);
+        }
+    }
+
+    /// Forgets what connection `id` was sent, once it left the room
+    fn unsubscribe(&self, id: u64) {
+        self.versions.lock().unwrap().remove(&id);
+    }
+
+    /// Removes the tombstones every connection has seen deleted. Operations
+    /// a client sent before the deletion reached it may still be on their
+    /// way, so only what all connections had been sent at the previous
+    /// collection counts as seen.
+    fn collect_garbage(&self, rga: &mut RGA, stable: &mut VersionVector) {
+        let mut current = rga.version().clone();
+        for version in self.versions.lock().unwrap().values() {
+            current.intersect(version);
+        }
+        rga.collect_garbage([&*stable, &current]);
+        *stable = current;
+    }
+
+    /// Records connection `origin`'s awareness state, or clears it, and
+    /// relays the change
+    fn set_presence(&self, origin: u64, state: Option<Awareness>) {
+        let mut presence = self.presence.lock().unwrap();
+        match &state {
+            Some(awareness) => {
+                presence.insert(origin, (awareness.clone(), Instant::now()));
+            }
+            None if presence.remove(&origin).is_none() => return,
+            None => (),
+        }
+        let _ = self.broadcaster.send(Update::Presence { origin, state });
+    }
+
+    /// Clears the awareness states not refreshed within `timeout`
+    fn expire_presence(&self, timeout: Duration) {
+        let mut presence = self.presence.lock().unwrap();
+        presence.retain(|origin, (_, updated)| {
+            let fresh = updated.elapsed() < timeout;
+            if !fresh {
+                let _ = self.broadcaster.send(Update::Presence {
+                    origin: *origin,
+                    state: None,
+                });
+            }
+            fresh
+        });
+    }
+
+    /// Applies an edit connection `origin` sent to the room and relays it. A
+    /// CRDT operation is acknowledged once the store has it, and its sender
+    /// told if the store fails.
+    async fn receive(&self, origin: u64, edit: Edit) -> Result<(), CollaboriError> {
+        let Some((id, appended)) = self.apply(origin, edit)? else {
+            return Ok(());
+        };
+        let stored = match appended {
+            Some(appended) => appended
+                .await
+                .unwrap_or_else(|_| Err("store writer stopped".to_string())),
+            None => Ok(()),
+        };
+        let update = match stored {
+            Ok(()) => Update::Stored { origin, id },
+            Err(message) => Update::StoreFailed { origin, message },
+        };
+        let _ = self.broadcaster.send(update);
+        Ok(())
+    }
+
+    /// Applies and relays an edit, returning the id of a CRDT operation and
+    /// what tells when the store has it
+    fn apply(
+        &self,
+        origin: u64,
+        edit: Edit,
+    ) -> Result<Option<(ElementId, Option<Appended>)>, CollaboriError> {
+        let mut state = self.state.lock().unwrap();
+        match (&mut *state, edit) {
+            (
+                DocumentState::Crdt {
+                    rga,
+                    logged,
+                    stable,
+                },
+                Edit::Crdt(op),
+            ) => {
+                rga.try_apply(&op)?;
+                *logged += 1;
+                let appended = self
+                    .store
+                    .as_ref()
+                    .map(|store| store.append(&self.document, op.clone()));
+                if *logged >= self.snapshot_interval {
+                    self.collect_garbage(rga, stable);
+                    if let Some(store) = &self.store {
+                        store.snapshot(&self.document, rga.as_ref().clone());
+                    }
+                    *logged = 0;
+                }
+                let id = op.last_id();
+                let _ = self.broadcaster.send(Update::Operation { origin, op });
+                Ok(Some((id, appended)))
+            }
+            (
+                DocumentState::Ot { document, origins },
+                Edit::Text {
+                    revision,
+                    operation,
+                },
+            ) => {
+                let operation = document.receive(revision, operation)?;
+                origins.push(origin);
+                let _ = self.broadcaster.send(Update::Revision {
+                    origin,
+                    revision: document.revision(),
+                    operation,
+                });
+                Ok(None)
+            }
+            (DocumentState::Crdt { .. }, Edit::Text { .. }) => {
+                Err(CollaboriError::ProtocolViolation(
+                    "text operations sent to a CRDT document".to_string(),
+                ))
+            }
+            (DocumentState::Ot { .. }, Edit::Crdt(_)) => Err(CollaboriError::ProtocolViolation(
+                "CRDT operations sent to an OT document".to_string(),
+            )),
+        }
+    }
+
+    /// Snapshots the document if operations were logged since the last one
+    fn save(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let (
+            Some(store),
+            DocumentState::Crdt {
+                rga,
+                logged,
+                stable,
+            },
+        ) = (&self.store, &mut *state)
+        {
+            if *logged > 0 {
+                self.collect_garbage(rga, stable);
+                store.snapshot(&self.document, rga.as_ref().clone());
+                *logged = 0;
+            }
+        }
+    }
+}
+
+/// State shared by all connections of a server
+#[derive(Debug, Clone)]
+struct Shared {
+    rooms: Arc<Mutex<HashMap<String, Arc<Room>>>>,
+    config: Arc<ServerConfig>,
+    /// Connections being served, counted against `max_connections`
+    connections: Arc<AtomicUsize>,
+    store: Option<StoreWriter>,
+}
+
+impl Shared {
+    /// Enters a document's room as `user`, returning the room and the user's
+    /// role in it
+    async fn enter(
+        &self,
+        user: Option<&Identity>,
+        document: &str,
+    ) -> Result<(Arc<Room>, Role), CollaboriError> {
+        let role = self.authorize(user, document)?;
+        Ok((self.join(document).await?, role))
+    }
+
+    /// Enters a document's room, opening it if nobody is editing it
+    async fn join(&self, document: &str) -> Result<Arc<Room>, CollaboriError> {
+        if let Some(room) = self.rooms.lock().unwrap().get(document) {
+            room.clients.fetch_add(1, Ordering::Relaxed);
+            return Ok(room.clone());
+        }
+        // Loading runs behind the snapshot of any room just closed on the
+        // document; whoever opens the room first wins
+        let rga = match (&self.store, self.config.mode) {
+            (Some(store), SyncMode::Crdt) => store.load(document).await?,
+            _ => None,
+        };
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = rooms
+            .entry(document.to_string())
+            .or_insert_with(|| Arc::new(Room::open(self, document, rga)));
+        room.clients.fetch_add(1, Ordering::Relaxed);
+        Ok(room.clone())
+    }
+
+    /// Returns `user`'s role in `document`. Without an authenticator everyone
+    /// owns every document
+    fn authorize(&self, user: Option<&Identity>, document: &str) -> Result<Role, CollaboriError> {
+        match (&self.config.authenticator, user) {
+            (Some(authenticator), Some(user)) => authenticator.role(user, document),
+            (Some(_), None) => Err(CollaboriError::Unauthorized(
+                "connection is not authenticated".to_string(),
+            )),
+            (None, _) => Ok(Role::Owner),
+        }
+    }
+
+    /// Clears the stale awareness states in every room
+    fn expire_presence(&self) {
+        let rooms: Vec<Arc<Room>> = self.rooms.lock().unwrap().values().cloned().collect();
+        for room in rooms {
+            room.expire_presence(self.config.presence_timeout);
+        }
+    }
+
+    /// Saves and tears down the rooms whose clients never left, such as
+    /// those of connections dropped at shutdown
+    fn close_rooms(&self) {
+        let rooms: Vec<Arc<Room>> = self
+            .rooms
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, room)| room)
+            .collect();
+        for room in rooms {
+            room.save();
+        }
+    }
+
+    /// Leaves a document's room. After the last client a room is saved and
+    /// torn down, unless there is no store to reopen it from; then it keeps
+    /// the document for the clients coming back.
+    fn leave(&self, document: &str) {
+        let mut rooms = self.rooms.lock().unwrap();
+        if let Some(room) = rooms.get(document) {
+            if room.clients.fetch_sub(1, Ordering::Relaxed) == 1 && room.store.is_some() {
+                room.save();
+                rooms.remove(document);
+            }
+        }
+    }
 }
 
 impl Default for SyncManager {
@@ -19,45 +500,224 @@ impl Default for SyncManager {
 impl SyncManager {
     /// Initializes the synchronization manager
     pub fn new() -> Self {
-        let (tx, _) = broadcast::channel(100);
+        Self::with_mode(SyncMode::default())
+    }
+
+    /// Initializes a synchronization manager handling operations in `mode`
+    pub fn with_mode(mode: SyncMode) -> Self {
+        Self::with_config(ServerConfig {
+            mode,
+            ..ServerConfig::default()
+        })
+    }
+
+    /// Initializes a synchronization manager with every setting in `config`
+    pub fn with_config(config: ServerConfig) -> Self {
         let (shutdown_tx, _) = broadcast::channel(1);
         SyncManager {
-            broadcaster: tx,
             shutdown: shutdown_tx,
+            rooms: Arc::new(Mutex::new(HashMap::new())),
+            config: ServerConfig {
+                snapshot_interval: config.snapshot_interval.max(1),
+                presence_timeout: config.presence_timeout.max(Duration::from_millis(1)),
+                // Zero turns the optional timeouts off, as in a config file
+                idle_timeout: config.idle_timeout.filter(|timeout| !timeout.is_zero()),
+                handshake_timeout: config
+                    .handshake_timeout
+                    .filter(|timeout| !timeout.is_zero()),
+                room_capacity: config.room_capacity.max(1),
+                connection_capacity: config.connection_capacity.max(1),
+                ..config
+            },
+        }
+    }
+
+    /// Persists documents edited in [`SyncMode::Crdt`] to `store`, restoring
+    /// them from it when their room opens. Servers in [`SyncMode::Ot`] refuse
+    /// to start with a store.
+    pub fn with_store(mut self, store: Arc<dyn DocumentStore>) -> Self {
+        self.config.store = Some(store);
+        self
+    }
+
+    /// Sets how many operations a room logs before snapshotting its document
+    pub fn with_snapshot_interval(mut self, snapshot_interval: usize) -> Self {
+        self.config.snapshot_interval = snapshot_interval.max(1);
+        self
+    }
+
+    /// Sets how long a client's awareness state lasts without being
+    /// refreshed before the other clients are told it left
+    pub fn with_presence_timeout(mut self, presence_timeout: Duration) -> Self {
+        self.config.presence_timeout = presence_timeout.max(Duration::from_millis(1));
+        self
+    }
+
+    /// Requires connections to authenticate with `authenticator`, which also
+    /// decides each user's role in the documents they open
+    pub fn with_authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.config.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Sets how many updates a room buffers for connections that haven't
+    /// sent them yet. A connection falling further behind lags, and is
+    /// handled according to the [`LagPolicy`].
+    pub fn with_room_capacity(mut self, room_capacity: usize) -> Self {
+        self.config.room_capacity = room_capacity.max(1);
+        self
+    }
+
+    /// Sets how many messages a connection queues while its socket is busy
+    pub fn with_connection_capacity(mut self, connection_capacity: usize) -> Self {
+        self.config.connection_capacity = connection_capacity.max(1);
+        self
+    }
+
+    /// Sets what happens to connections that fall more than the room's
+    /// capacity behind
+    pub fn with_lag_policy(mut self, lag_policy: LagPolicy) -> Self {
+        self.config.lag_policy = lag_policy;
+        self
+    }
+
+    pub fn mode(&self) -> SyncMode {
+        self.config.mode
+    }
+
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    /// Returns the ids of the documents that currently have clients
+    pub fn documents(&self) -> Vec<String> {
+        let rooms = self.rooms.lock().unwrap();
+        rooms
+            .iter()
+            .filter(|(_, room)| room.clients.load(Ordering::Relaxed) > 0)
+            .map(|(document, _)| document.clone())
+            .collect()
+    }
+
+    /// Returns a copy of a document edited in [`SyncMode::Crdt`], if its room
+    /// is open
+    pub fn rga(&self, document: &str) -> Option<RGA> {
+        let rooms = self.rooms.lock().unwrap();
+        let state = rooms.get(document)?.state.lock().unwrap();
+        match &*state {
+            DocumentState::Crdt { rga, .. } => Some(rga.as_ref().clone()),
+            DocumentState::Ot { .. } => None,
+        }
+    }
+
+    /// Returns a copy of a document edited in [`SyncMode::Ot`], if its room
+    /// is open
+    pub fn ot_document(&self, document: &str) -> Option<OTDocument> {
+        let rooms = self.rooms.lock().unwrap();
+        let state = rooms.get(document)?.state.lock().unwrap();
+        match &*state {
+            DocumentState::Ot { document, .. } => Some(document.clone()),
+            DocumentState::Crdt { .. } => None,
         }
     }
 
-    /// Starts the WebSocket server
-    pub async fn start_server(&self, addr: &str) -> mpsc::Receiver<()> {
-        let listener = TcpListener::bind(addr).await.expect("Failed to bind");
-        println!("WebSocket server listening on {}", addr);
+    fn shared(&self) -> Shared {
+        Shared {
+            rooms: self.rooms.clone(),
+            config: Arc::new(self.config.clone()),
+            connections: Arc::new(AtomicUsize::new(0)),
+            store: self.config.store.clone().map(StoreWriter::spawn),
+        }
+    }
+
+    /// Starts the WebSocket server on the configured address
+    pub async fn start(&self) -> Result<ServerHandle, CollaboriError> {
+        self.start_server(&self.config.address).await
+    }
+
+    /// Starts the WebSocket server. Bind to port 0 to have the OS pick a free
+    /// port, then read it from [`ServerHandle::local_addr`].
+    pub async fn start_server(&self, addr: &str) -> Result<ServerHandle, CollaboriError> {
+        let listener =
+            TcpListener::bind(addr)
+                .await
+                .map_err(|source| CollaboriError::BindError {
+                    addr: addr.to_string(),
+                    source,
+                })?;
+        self.serve(listener)
+    }
+
+    /// Starts the WebSocket server on a listener the caller has already
+    /// bound and configured
+    pub fn serve(&self, listener: TcpListener) -> Result<ServerHandle, CollaboriError> {
+        self.config.validate()?;
+        if let Some(store) = &self.config.store {
+            store.init()?;
+        }
+        let local_addr = listener.local_addr()?;
+        println!("WebSocket server listening on {}", local_addr);
 
         let (shutdown_confirmation_tx, shutdown_confirmation_rx) = mpsc::channel(1);
         let mut shutdown_rx = self.shutdown.subscribe();
-        let broadcaster = self.broadcaster.clone();
+        let shared = self.shared();
+        let next_connection_id = AtomicU64::new(0);
+        let mut sweep = tokio::time::interval(self.config.presence_timeout / 2);
+        let shutdown_timeout = self.config.shutdown_timeout;
+        let (closing_tx, closing_rx) = watch::channel(false);
 
         tokio::spawn(async move {
+            let mut connections = JoinSet::new();
             loop {
                 tokio::select! {
                     Ok((stream, _)) = listener.accept() => {
-                        let ws_stream = accept_async(stream).await.expect("Failed to accept");
-                        let broadcaster = broadcaster.clone();
-                        tokio::spawn(handle_connection(ws_stream, broadcaster));
+                        let id = next_connection_id.fetch_add(1, Ordering::Relaxed);
+                        let closing = closing_rx.clone();
+                        connections.spawn(handle_connection(stream, shared.clone(), id, closing));
                     }
+                    Some(_) = connections.join_next() => (),
+                    _ = sweep.tick() => shared.expire_presence(),
                     _ = shutdown_rx.recv() => {
                         println!("Shutting down server");
                         break;
                     }
                 }
             }
+            drop(listener);
+
+            // Close every session, giving them until the deadline to finish
+            let _ = closing_tx.send(true);
+            let drained = timeout(shutdown_timeout, async {
+                while connections.join_next().await.is_some() {}
+            })
+            .await;
+            if drained.is_err() {
+                println!(
+                    "Aborting {} connections after the shutdown deadline",
+                    connections.len()
+                );
+                connections.shutdown().await;
+            }
+            shared.close_rooms();
+            if let Some(store) = &shared.store {
+                store.flush().await;
+            }
+
             // Notify that the server has shut down
             let _ = shutdown_confirmation_tx.send(()).await;
         });
 
-        shutdown_confirmation_rx
+        Ok(ServerHandle {
+            local_addr,
+            shutdown: self.shutdown.clone(),
+            stopped: shutdown_confirmation_rx,
+        })
     }
 
-    /// Sends a shutdown signal to the server
+    /// Sends a shutdown signal to the server, which stops accepting
+    /// connections, closes the open ones with code 1001 (going away) and saves
+    /// their documents. Connections still open after the configured
+    /// `shutdown_timeout` are dropped.
     pub async fn shutdown(&self) {
         if let Err(err) = self.shutdown.send(()) {
             println!("Failed to send shutdown signal: {}", err);
@@ -65,92 +725,1646 @@ impl SyncManager {
     }
 }
 
+/// A running server, returned by [`SyncManager::start_server`]. The server
+/// keeps running while either the handle or its `SyncManager` is alive.
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    shutdown: broadcast::Sender<()>,
+    stopped: mpsc::Receiver<()>,
+}
+
+impl ServerHandle {
+    /// Returns the address the server accepts connections on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Sends a shutdown signal to the server, like [`SyncManager::shutdown`]
+    pub fn shutdown(&self) {
+        let _ = self.shutdown.send(());
+    }
+
+    /// Waits until the server has shut down: it no longer accepts
+    /// connections, its sessions have ended and its documents are saved
+    pub async fn stopped(&mut self) {
+        self.stopped.recv().await;
+    }
+}
+
+/// Returns the document named by a request path such as `/notes`
+fn document_from_path(path: &str) -> Option<String> {
+    let document = path.trim_matches('/');
+    (!document.is_empty()).then(|| document.to_string())
+}
+
+/// Returns the revision to resume from in a query such as `revision=12`
+fn revision_from_query(query: &str) -> Option<usize> {
+    url::form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == "revision")
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+/// The error code telling a client why it couldn't enter a room
+fn join_error_code(err: &CollaboriError) -> ErrorCode {
+    match err {
+        CollaboriError::Unauthorized(_) => ErrorCode::PermissionDenied,
+        _ => ErrorCode::DocumentUnavailable,
+    }
+}
+
+/// Returns the bearer token in a request's `Authorization` header or its
+/// `access_token` query parameter
+fn token_from_request(request: &Request) -> Option<String> {
+    let header = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match header {
+        Some(token) => Some(token.trim().to_string()),
+        None => url::form_urlencoded::parse(request.uri().query()?.as_bytes())
+            .find(|(key, _)| key == "access_token")
+            .map(|(_, value)| value.into_owned()),
+    }
+}
+
+/// An HTTP error answering a WebSocket handshake
+fn rejection(status: StatusCode, reason: String) -> ErrorResponse {
+    let mut rejection = ErrorResponse::new(Some(reason));
+    *rejection.status_mut() = status;
+    rejection
+}
+
+fn close_frame(code: CloseCode, reason: &str) -> Message {
+    Message::Close(Some(CloseFrame {
+        code,
+        reason: reason.into(),
+    }))
+}
+
+/// Waits until `deadline`, or forever without one
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Counts a connection against `max_connections` until dropped
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    /// Takes a slot, returning it and how many connections are now served
+    fn take(connections: &Arc<AtomicUsize>) -> (Self, usize) {
+        let served = connections.fetch_add(1, Ordering::Relaxed) + 1;
+        (ConnectionSlot(connections.clone()), served)
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A task serving part of a connection, aborted when dropped so it can't
+/// outlive the connection, e.g. one the server aborts at shutdown
+struct ConnectionTask(JoinHandle<()>);
+
+impl ConnectionTask {
+    fn spawn(task: impl Future<Output = ()> + Send + 'static) -> Self {
+        ConnectionTask(tokio::spawn(task))
+    }
+
+    fn abort(&self) {
+        self.0.abort();
+    }
+}
+
+impl Drop for ConnectionTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Serves one client until it leaves or `closing` turns true as the server
+/// shuts down
 async fn handle_connection(
-    ws_stream: tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>,
-    broadcaster: broadcast::Sender<Operation>,
+    stream: TcpStream,
+    shared: Shared,
+    id: u64,
+    mut closing: watch::Receiver<bool>,
 ) {
+    let config = shared.config.clone();
+    let mut slot = None;
+    let mut document = DEFAULT_DOCUMENT.to_string();
+    let mut revision = None;
+    let mut identity = None;
+    // The error type is fixed by tungstenite's handshake callback
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, response: Response| {
+        let origin = request
+            .headers()
+            .get(ORIGIN)
+            .and_then(|value| value.to_str().ok());
+        if !config.allows_origin(origin) {
+            let reason = format!("origin {} is not allowed", origin.unwrap_or("(none)"));
+            return Err(rejection(StatusCode::FORBIDDEN, reason));
+        }
+        if let Some(path_document) = document_from_path(request.uri().path()) {
+            document = path_document;
+        }
+        revision = request.uri().query().and_then(revision_from_query);
+        if let Some(authenticator) = &config.authenticator {
+            match authenticator.authenticate(token_from_request(request).as_deref()) {
+                Ok(user) => identity = Some(user),
+                Err(err) => return Err(rejection(StatusCode::UNAUTHORIZED, err.to_string())),
+            }
+        }
+        let (taken, served) = ConnectionSlot::take(&shared.connections);
+        if config.max_connections.is_some_and(|max| served > max) {
+            let reason = "too many connections".to_string();
+            return Err(rejection(StatusCode::SERVICE_UNAVAILABLE, reason));
+        }
+        slot = Some(taken);
+        Ok(response)
+    };
+    let ws_config = config.max_message_size.map(|max| {
+        WebSocketConfig::default()
+            .max_message_size(Some(max))
+            .max_frame_size(Some(max))
+    });
+    // Connections that never finish their handshake would hold a slot
+    let handshake_deadline = config
+        .handshake_timeout
+        .map(|timeout| Instant::now() + timeout);
+    let accepted = tokio::select! {
+        accepted = accept_hdr_async_with_config(stream, callback, ws_config) => accepted,
+        _ = closing.changed() => return,
+        _ = sleep_until(handshake_deadline) => return,
+    };
+    let ws_stream = match accepted {
+        Ok(ws_stream) => ws_stream,
+        Err(err) => {
+            println!("Failed to accept connection: {}", err);
+            return;
+        }
+    };
     let (mut write, mut read) = ws_stream.split();
-    let mut rx = broadcaster.subscribe();
-
-    // Spawn a task to forward broadcast messages to the client
-    let forward = tokio::spawn(async move {
-        while let Ok(op) = rx.recv().await {
-            let msg = serde_json::to_string(&op).unwrap();
-            if write
-                .send(tokio_tungstenite::tungstenite::Message::Text(msg))
-                .await
-                .is_err()
-            {
+
+    // A single writer lets the connection switch rooms without losing the sink
+    let (outgoing, mut outgoing_rx) = mpsc::channel::<Message>(config.connection_capacity);
+    let writer = ConnectionTask::spawn(async move {
+        while let Some(msg) = outgoing_rx.recv().await {
+            let close = matches!(msg, Message::Close(_));
+            if write.send(msg).await.is_err() || close {
                 break;
             }
         }
     });
 
-    // Read messages from the client and broadcast them
-    while let Some(msg) = read.next().await {
-        match msg {
-            Ok(tokio_tungstenite::tungstenite::Message::Text(text)) => {
-                if let Ok(op) = serde_json::from_str::<Operation>(&text) {
-                    let _ = broadcaster.send(op);
+    let handshaken = tokio::select! {
+        handshaken = handshake(&mut read, &outgoing) => handshaken,
+        _ = closing.changed() => {
+            let _ = outgoing.try_send(close_frame(CloseCode::Away, "server shutting down"));
+            flush(outgoing, writer).await;
+            return;
+        }
+        _ = sleep_until(handshake_deadline) => {
+            let _ = outgoing.try_send(close_frame(CloseCode::Policy, "handshake timeout"));
+            flush(outgoing, writer).await;
+            return;
+        }
+    };
+    let codec = match handshaken {
+        Ok((_, codec)) => codec,
+        Err(err) => {
+            println!("Handshake failed: {}", err);
+            let _ = outgoing.try_send(Message::Close(None));
+            flush(outgoing, writer).await;
+            return;
+        }
+    };
+    let reply = |envelope: Envelope| {
+        let outgoing = outgoing.clone();
+        async move {
+            let _ = outgoing.send(codec.encode(&envelope)).await;
+        }
+    };
+
+    let joined = shared.enter(identity.as_ref(), &document).await;
+    let (mut room, mut role) = match joined {
+        Ok(joined) => joined,
+        Err(err) => {
+            reply(Envelope::error(join_error_code(&err), err.to_string())).await;
+            let _ = outgoing.try_send(Message::Close(None));
+            flush(outgoing, writer).await;
+            return;
+        }
+    };
+    let mut forward = ConnectionTask::spawn(forward_updates(
+        room.clone(),
+        room.subscribe(id, revision),
+        id,
+        codec,
+        outgoing.clone(),
+        shared.config.lag_policy,
+    ));
+
+    let mut heartbeat = Heartbeat::new(config.ping_interval, config.max_missed_pings);
+    let mut last_heard = Instant::now();
+
+    // Read messages from the client and hand them to its room
+    loop {
+        let msg = tokio::select! {
+            msg = read.next() => msg,
+            _ = closing.changed() => {
+                let _ = outgoing.try_send(close_frame(CloseCode::Away, "server shutting down"));
+                break;
+            }
+            ping = heartbeat.tick() => {
+                match ping {
+                    Some(nonce) => {
+                        let ping = Message::Ping(nonce.to_be_bytes().to_vec().into());
+                        // A connection too backed up to take a ping misses it
+                        let _ = outgoing.try_send(ping);
+                    }
+                    None => {
+                        let reason = format!("missed {} heartbeats", heartbeat.missed());
+                        let _ = outgoing.try_send(close_frame(CloseCode::Policy, &reason));
+                        break;
+                    }
                 }
+                continue;
+            }
+            _ = sleep_until(config.idle_timeout.map(|timeout| last_heard + timeout)) => {
+                let _ = outgoing.try_send(close_frame(CloseCode::Policy, "idle timeout"));
+                break;
+            }
+        };
+        last_heard = Instant::now();
+        heartbeat.heard();
+        let decoded = match msg {
+            None | Some(Ok(Message::Close(_))) => break,
+            Some(Ok(Message::Pong(payload))) => {
+                if let Ok(nonce) = <[u8; 8]>::try_from(payload.as_ref()) {
+                    heartbeat.pong(u64::from_be_bytes(nonce));
+                }
+                continue;
+            }
+            Some(Ok(msg)) => Codec::decode(&msg),
+            Some(Err(WsError::Capacity(err))) => {
+                let reason = err.to_string();
+                let _ = outgoing.try_send(close_frame(CloseCode::Size, &reason));
+                break;
+            }
+            Some(Err(err)) => {
+                println!("Connection {} failed: {}", id, err);
+                break;
+            }
+        };
+        let envelope = match decoded {
+            Some(Ok(envelope)) => envelope,
+            None => continue,
+            Some(Err(err)) => {
+                reply(Envelope::error(ErrorCode::InvalidMessage, err.to_string())).await;
+                continue;
+            }
+        };
+        match envelope {
+            Envelope::Join {
+                document: joined,
+                revision,
+            } => {
+                if joined == document {
+                    continue;
+                }
+                let entered = shared.enter(identity.as_ref(), &joined).await;
+                let (joined_room, joined_role) = match entered {
+                    Ok(entered) => entered,
+                    Err(err) => {
+                        reply(Envelope::error(join_error_code(&err), err.to_string())).await;
+                        continue;
+                    }
+                };
+                forward.abort();
+                room.set_presence(id, None);
+                room.unsubscribe(id);
+                shared.leave(&document);
+                document = joined;
+                room = joined_room;
+                role = joined_role;
+                forward = ConnectionTask::spawn(forward_updates(
+                    room.clone(),
+                    room.subscribe(id, revision),
+                    id,
+                    codec,
+                    outgoing.clone(),
+                    shared.config.lag_policy,
+                ));
+            }
+            Envelope::Op { .. } if !role.can_edit() => {
+                let message = format!("A {:?} cannot edit {}", role, document);
+                reply(Envelope::error(ErrorCode::PermissionDenied, message)).await;
+            }
+            Envelope::Op { edit } => {
+                if let Err(err) = room.receive(id, edit).await {
+                    reply(Envelope::error(
+                        ErrorCode::RejectedOperation,
+                        err.to_string(),
+                    ))
+                    .await;
+                }
+            }
+            Envelope::Presence { mut state, .. } => {
+                // Authenticated users can only speak for themselves
+                if let (Some(state), Some(user)) = (&mut state, &identity) {
+                    state.user_id = user.user_id.clone();
+                }
+                room.set_presence(id, state)
+            }
+            Envelope::Ping { nonce } => reply(Envelope::Pong { nonce }).await,
+            Envelope::Pong { nonce } => {
+                heartbeat.pong(nonce);
+            }
+            other => {
+                let message = format!("Unexpected message from a client: {:?}", other);
+                reply(Envelope::error(ErrorCode::InvalidMessage, message)).await;
             }
-            Ok(tokio_tungstenite::tungstenite::Message::Close(_)) => break,
-            _ => (),
         }
     }
 
     forward.abort();
+    room.set_presence(id, None);
+    room.unsubscribe(id);
+    shared.leave(&document);
+
+    flush(outgoing, writer).await;
+}
+
+/// Lets a connection's writer send what is queued, such as a close frame,
+/// for at most `FLUSH_TIMEOUT`. Close frames are queued with `try_send`, so
+/// a connection whose queue is full is still reaped, without one.
+async fn flush(outgoing: mpsc::Sender<Message>, mut writer: ConnectionTask) {
+    drop(outgoing);
+    let _ = timeout(FLUSH_TIMEOUT, &mut writer.0).await;
+}
+
+/// Waits for the client's `Hello` and answers with the version to speak, in
+/// the codec the `Hello` arrived in
+async fn handshake(
+    read: &mut SplitStream<WebSocketStream<TcpStream>>,
+    outgoing: &mpsc::Sender<Message>,
+) -> Result<(u32, Codec), CollaboriError> {
+    let (codec, hello) = loop {
+        match read.next().await {
+            Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+            Some(Ok(msg @ (Message::Text(_) | Message::Binary(_)))) => {
+                break (Codec::of(&msg).unwrap_or_default(), Codec::decode(&msg))
+            }
+            Some(Ok(_)) | None => {
+                return Err(CollaboriError::ProtocolViolation(
+                    "connection closed before hello".to_string(),
+                ))
+            }
+            Some(Err(err)) => return Err(err.into()),
+        }
+    };
+    let (code, message) = match hello {
+        Some(Ok(Envelope::Hello {
+            version,
+            min_version,
+        })) => match negotiate(version, min_version) {
+            Some(version) => {
+                let hello = Envelope::Hello {
+                    version,
+                    min_version: version,
+                };
+                let _ = outgoing.send(codec.encode(&hello)).await;
+                return Ok((version, codec));
+            }
+            None => (
+                ErrorCode::UnsupportedVersion,
+                format!(
+                    "No shared protocol version in {}..={}",
+                    min_version, version
+                ),
+            ),
+        },
+        Some(Ok(other)) => (
+            ErrorCode::InvalidMessage,
+            format!("Expected hello, got {:?}", other),
+        ),
+        Some(Err(err)) => (ErrorCode::InvalidMessage, err.to_string()),
+        None => (ErrorCode::InvalidMessage, "Expected hello".to_string()),
+    };
+    let _ = outgoing
+        .send(codec.encode(&Envelope::error(code, message.clone())))
+        .await;
+    Err(CollaboriError::ProtocolViolation(message))
+}
+
+/// Sends the catch-up messages to connection `id`, then forwards its room's
+/// updates. If the connection falls too far behind, `lag_policy` decides
+/// whether it catches up again or is closed.
+async fn forward_updates(
+    room: Arc<Room>,
+    mut subscription: Subscription,
+    id: u64,
+    codec: Codec,
+    outgoing: mpsc::Sender<Message>,
+    lag_policy: LagPolicy,
+) {
+    loop {
+        for envelope in subscription.catch_up.drain(..) {
+            if outgoing.send(codec.encode(&envelope)).await.is_err() {
+                return;
+            }
+        }
+        if forward_subscription(&room, &mut subscription, id, codec, &outgoing)
+            .await
+            .is_none()
+        {
+            return;
+        }
+        match lag_policy {
+            LagPolicy::Resync => subscription = room.subscribe(id, subscription.revision),
+            LagPolicy::Disconnect => {
+                let close = close_frame(CloseCode::Again, "fell too far behind the room");
+                let _ = outgoing.send(close).await;
+                return;
+            }
+        }
+    }
+}
+
+/// Forwards updates until the subscription lags, returning `None` once the
+/// room or the connection is gone
+async fn forward_subscription(
+    room: &Room,
+    subscription: &mut Subscription,
+    id: u64,
+    codec: Codec,
+    outgoing: &mpsc::Sender<Message>,
+) -> Option<()> {
+    loop {
+        let update = match subscription.updates.recv().await {
+            Ok(update) => update,
+            Err(RecvError::Lagged(skipped)) => {
+                println!("Connection {} missed {} updates", id, skipped);
+                return Some(());
+            }
+            Err(RecvError::Closed) => return None,
+        };
+        match &update {
+            Update::Operation { op, .. } => room.observe(id, op.last_id()),
+            Update::Revision { revision, .. } => subscription.revision = Some(*revision),
+            _ => (),
+        }
+        let envelope = match update {
+            // Senders get an ack instead of their own edit back, once it is
+            // stored. Acks travel through the broadcast so they stay ordered
+            // with the updates before them
+            Update::Operation { origin, .. } if origin == id => continue,
+            Update::Operation { op, .. } => Envelope::Op {
+                edit: Edit::Crdt(op),
+            },
+            Update::Stored { origin, id: op } if origin == id => Envelope::Ack {
+                id: Some(op),
+                revision: None,
+            },
+            Update::StoreFailed { origin, message } if origin == id => {
+                Envelope::error(ErrorCode::StorageFailed, message)
+            }
+            Update::Stored { .. } | Update::StoreFailed { .. } => continue,
+            Update::Revision {
+                origin, revision, ..
+            } if origin == id => Envelope::Ack {
+                id: None,
+                revision: Some(revision),
+            },
+            Update::Revision {
+                revision,
+                operation,
+                ..
+            } => Envelope::Op {
+                edit: Edit::Text {
+                    revision: revision - 1,
+                    operation,
+                },
+            },
+            Update::Presence { origin, .. } if origin == id => continue,
+            Update::Presence { origin, state } => Envelope::Presence {
+                client: Some(origin),
+                state,
+            },
+        };
+        outgoing.send(codec.encode(&envelope)).await.ok()?;
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::auth::StaticAuthenticator;
+    use crate::data::ElementId;
+    use crate::protocol::PROTOCOL_VERSION;
+    use std::sync::atomic::AtomicBool;
+    use tokio::io::AsyncReadExt;
     use tokio::time::{timeout, Duration};
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::handshake::client::Request as ClientRequest;
     use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
     use url::Url;
 
-    #[tokio::test]
-    async fn test_sync_manager() {
+    type Client = tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >;
+
+    async fn send(ws_stream: &mut Client, envelope: &Envelope) {
+        ws_stream.send(Codec::Json.encode(envelope)).await.unwrap();
+    }
+
+    async fn next_envelope(ws_stream: &mut Client) -> Envelope {
+        match timeout(Duration::from_secs(1), ws_stream.next()).await {
+            Ok(Some(Ok(Message::Text(text)))) => serde_json::from_str(&text).unwrap(),
+            other => panic!("Did not receive a message: {:?}", other),
+        }
+    }
+
+    /// Connects and completes the protocol handshake
+    async fn connect(url: &str) -> Client {
+        connect_request(url.into_client_request().unwrap()).await
+    }
+
+    async fn connect_request(request: ClientRequest) -> Client {
+        let (mut ws_stream, _) = connect_async(request).await.expect("Failed to connect");
+        send(&mut ws_stream, &Envelope::hello()).await;
+        match next_envelope(&mut ws_stream).await {
+            Envelope::Hello { version, .. } => assert_eq!(version, PROTOCOL_VERSION),
+            other => panic!("Expected hello, got {:?}", other),
+        }
+        ws_stream
+    }
+
+    async fn expect_snapshot(ws_stream: &mut Client) -> RGA {
+        match next_envelope(ws_stream).await {
+            Envelope::Snapshot {
+                state: DocumentSnapshot::Crdt(rga),
+                ..
+            } => *rga,
+            other => panic!("Expected a snapshot, got {:?}", other),
+        }
+    }
+
+    async fn expect_op(ws_stream: &mut Client) -> Edit {
+        match next_envelope(ws_stream).await {
+            Envelope::Op { edit } => edit,
+            other => panic!("Expected an operation, got {:?}", other),
+        }
+    }
+
+    fn crdt_op(op: &Operation) -> Envelope {
+        Envelope::Op {
+            edit: Edit::Crdt(op.clone()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_manager() {
         let sync_manager = SyncManager::new();
-        let addr = "127.0.0.1:9001";
 
         // Start the server in a background task
-        let mut shutdown_rx = sync_manager.start_server(addr).await;
+        let mut server = sync_manager.start_server("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr();
 
         // Give the server a moment to start
         tokio::time::sleep(Duration::from_millis(100)).await;
 
         // Connect a client to the server
         let url = Url::parse(&format!("ws://{}", addr)).unwrap();
-        let (mut ws_stream, _) = connect_async(url.as_str())
-            .await
-            .expect("Failed to connect");
+        let mut ws_stream = connect(url.as_str()).await;
+        assert!(expect_snapshot(&mut ws_stream).await.is_empty());
+        let mut peer = connect(url.as_str()).await;
+        expect_snapshot(&mut peer).await;
 
         // Send a message from the client
         let op = Operation::Insert {
             index: 0,
             value: 'a',
-            id: "1".into(),
+            id: ElementId::new(1, 1),
+            origin: None,
         };
-        let msg = serde_json::to_string(&op).unwrap();
-        ws_stream.send(Message::Text(msg)).await.unwrap();
+        send(&mut ws_stream, &crdt_op(&op)).await;
 
-        // Receive the broadcasted message
-        let result = timeout(Duration::from_secs(1), ws_stream.next()).await;
-        match result {
-            Ok(Some(Ok(Message::Text(received_msg)))) => {
-                let received_op: Operation = serde_json::from_str(&received_msg).unwrap();
-                assert_eq!(received_op, op);
-            }
-            _ => panic!("Did not receive the expected message"),
+        // The sender gets an ack, not its own operation
+        match next_envelope(&mut ws_stream).await {
+            Envelope::Ack { id, .. } => assert_eq!(id, Some(ElementId::new(1, 1))),
+            other => panic!("Did not receive the expected ack: {:?}", other),
         }
 
+        // Receive the broadcasted message
+        assert_eq!(expect_op(&mut peer).await, Edit::Crdt(op));
+
         // Stop the server
         sync_manager.shutdown().await;
 
         // Wait for the server to shut down
-        timeout(Duration::from_secs(5), shutdown_rx.recv())
+        timeout(Duration::from_secs(5), server.stopped())
+            .await
+            .expect("Server didn't shut down in time");
+    }
+
+    #[tokio::test]
+    async fn test_sync_manager_relays_text_operations() {
+        let sync_manager = SyncManager::new();
+        let mut server = sync_manager.start_server("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let url = Url::parse(&format!("ws://{}", addr)).unwrap();
+        let mut ws_stream = connect(url.as_str()).await;
+        expect_snapshot(&mut ws_stream).await;
+        let mut peer = connect(url.as_str()).await;
+        expect_snapshot(&mut peer).await;
+
+        // A whole paragraph and a range delete each travel as one message
+        let ops = vec![
+            Operation::InsertText {
+                index: 0,
+                text: "hello world".repeat(100),
+                id: ElementId::new(1, 1),
+                origin: None,
+            },
+            Operation::DeleteRange {
+                index: 5,
+                len: 6,
+                ids: (7..13).map(|c| ElementId::new(1, c)).collect(),
+                stamp: ElementId::new(1, 1101),
+            },
+        ];
+        for op in &ops {
+            send(&mut ws_stream, &crdt_op(op)).await;
+        }
+        for op in &ops {
+            assert_eq!(expect_op(&mut peer).await, Edit::Crdt(op.clone()));
+        }
+
+        sync_manager.shutdown().await;
+        timeout(Duration::from_secs(5), server.stopped())
+            .await
+            .expect("Server didn't shut down in time");
+    }
+
+    #[tokio::test]
+    async fn test_sync_manager_serves_a_bound_listener() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let sync_manager = SyncManager::new();
+        let mut server = sync_manager.serve(listener).unwrap();
+        assert_eq!(server.local_addr(), addr);
+
+        let mut ws_stream = connect(&format!("ws://{}", addr)).await;
+        assert_eq!(expect_snapshot(&mut ws_stream).await.text(), "");
+
+        // The handle stops the server on its own
+        server.shutdown();
+        timeout(Duration::from_secs(5), server.stopped())
+            .await
+            .expect("Server didn't shut down in time");
+    }
+
+    #[tokio::test]
+    async fn test_sync_manager_negotiates_protocol_version() {
+        let sync_manager = SyncManager::new();
+        let mut server = sync_manager.start_server("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let url = format!("ws://{}", addr);
+
+        // A client only speaking future versions is turned away
+        let (mut future, _) = connect_async(&url).await.unwrap();
+        let hello = Envelope::Hello {
+            version: PROTOCOL_VERSION + 2,
+            min_version: PROTOCOL_VERSION + 1,
+        };
+        send(&mut future, &hello).await;
+        assert!(matches!(
+            next_envelope(&mut future).await,
+            Envelope::Error {
+                code: ErrorCode::UnsupportedVersion,
+                ..
+            }
+        ));
+        assert!(matches!(
+            timeout(Duration::from_secs(1), future.next()).await,
+            Ok(Some(Ok(Message::Close(_))) | None)
+        ));
+
+        // Anything before the hello is a protocol violation
+        let (mut legacy, _) = connect_async(&url).await.unwrap();
+        send(&mut legacy, &Envelope::Ping { nonce: 1 }).await;
+        assert!(matches!(
+            next_envelope(&mut legacy).await,
+            Envelope::Error {
+                code: ErrorCode::InvalidMessage,
+                ..
+            }
+        ));
+
+        // Once connected, malformed messages are reported instead of dropped
+        let mut client = connect(&url).await;
+        expect_snapshot(&mut client).await;
+        client.send(Message::Text("{}".into())).await.unwrap();
+        assert!(matches!(
+            next_envelope(&mut client).await,
+            Envelope::Error {
+                code: ErrorCode::InvalidMessage,
+                ..
+            }
+        ));
+        send(&mut client, &Envelope::Ping { nonce: 7 }).await;
+        assert!(matches!(
+            next_envelope(&mut client).await,
+            Envelope::Pong { nonce: 7 }
+        ));
+
+        sync_manager.shutdown().await;
+        timeout(Duration::from_secs(5), server.stopped())
+            .await
+            .expect("Server didn't shut down in time");
+    }
+
+    fn text_op(revision: usize, operation: TextOperation) -> Envelope {
+        Envelope::Op {
+            edit: Edit::Text {
+                revision,
+                operation,
+            },
+        }
+    }
+
+    async fn expect_ack(ws_stream: &mut Client) -> usize {
+        match next_envelope(ws_stream).await {
+            Envelope::Ack {
+                revision: Some(revision),
+                ..
+            } => revision,
+            other => panic!("Expected an ack, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_manager_ot_revisions() {
+        let sync_manager = SyncManager::with_mode(SyncMode::Ot);
+        let mut server = sync_manager.start_server("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let url = Url::parse(&format!("ws://{}", addr)).unwrap();
+        let mut alice = connect(url.as_str()).await;
+        let mut bob = connect(url.as_str()).await;
+        for client in [&mut alice, &mut bob] {
+            assert!(matches!(
+                next_envelope(client).await,
+                Envelope::Snapshot {
+                    state: DocumentSnapshot::Text { revision: 0, text },
+                    ..
+                } if text.is_empty()
+            ));
+        }
+
+        // Both edit revision 0 concurrently: the second to arrive is
+        // transformed past the first
+        send(&mut alice, &text_op(0, TextOperation::new().insert("abc"))).await;
+        assert_eq!(expect_ack(&mut alice).await, 1);
+        send(&mut bob, &text_op(0, TextOperation::new().insert("X"))).await;
+
+        assert_eq!(
+            expect_op(&mut bob).await,
+            Edit::Text {
+                revision: 0,
+                operation: TextOperation::new().insert("abc"),
+            }
+        );
+        assert_eq!(expect_ack(&mut bob).await, 2);
+        assert_eq!(
+            expect_op(&mut alice).await,
+            Edit::Text {
+                revision: 1,
+                operation: TextOperation::new().insert("X").retain(3),
+            }
+        );
+
+        let document = sync_manager.ot_document(DEFAULT_DOCUMENT).unwrap();
+        assert_eq!(document.revision(), 2);
+        assert_eq!(document.text(), "Xabc");
+
+        // Operations on unknown revisions are rejected
+        send(&mut bob, &text_op(7, TextOperation::new().retain(4))).await;
+        assert!(matches!(
+            next_envelope(&mut bob).await,
+            Envelope::Error {
+                code: ErrorCode::RejectedOperation,
+                ..
+            }
+        ));
+
+        // A client resuming from revision 1 only gets what it missed
+        let mut carol = connect(&format!("ws://{}/?revision=1", addr)).await;
+        assert_eq!(
+            expect_op(&mut carol).await,
+            Edit::Text {
+                revision: 1,
+                operation: TextOperation::new().insert("X").retain(3),
+            }
+        );
+
+        sync_manager.shutdown().await;
+        timeout(Duration::from_secs(5), server.stopped())
+            .await
+            .expect("Server didn't shut down in time");
+    }
+
+    fn awareness(user_id: &str, cursor: usize) -> Awareness {
+        Awareness {
+            user_id: user_id.to_string(),
+            name: user_id.to_uppercase(),
+            color: "#e91e63".to_string(),
+            selection: Some(crate::data::Selection::cursor(cursor)),
+        }
+    }
+
+    async fn expect_presence(ws_stream: &mut Client) -> (u64, Option<Awareness>) {
+        match next_envelope(ws_stream).await {
+            Envelope::Presence {
+                client: Some(client),
+                state,
+            } => (client, state),
+            other => panic!("Expected presence, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_manager_relays_presence() {
+        let sync_manager = SyncManager::new().with_presence_timeout(Duration::from_millis(300));
+        let mut server = sync_manager.start_server("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/notes", server.local_addr());
+
+        let mut alice = connect(&url).await;
+        expect_snapshot(&mut alice).await;
+        let mut bob = connect(&url).await;
+        expect_snapshot(&mut bob).await;
+
+        let state = |user_id, cursor| Envelope::Presence {
+            client: None,
+            state: Some(awareness(user_id, cursor)),
+        };
+        send(&mut alice, &state("alice", 2)).await;
+        let (alice_id, seen) = expect_presence(&mut bob).await;
+        assert_eq!(seen, Some(awareness("alice", 2)));
+
+        // Late joiners see who is already there, after the document
+        let mut carol = connect(&url).await;
+        expect_snapshot(&mut carol).await;
+        assert_eq!(
+            expect_presence(&mut carol).await,
+            (alice_id, Some(awareness("alice", 2)))
+        );
+
+        // Leaving clears the state for the others
+        alice.close(None).await.unwrap();
+        assert_eq!(expect_presence(&mut bob).await, (alice_id, None));
+        assert_eq!(expect_presence(&mut carol).await, (alice_id, None));
+
+        // So does going quiet for longer than the timeout
+        send(&mut bob, &state("bob", 0)).await;
+        let (bob_id, _) = expect_presence(&mut carol).await;
+        assert_eq!(expect_presence(&mut carol).await, (bob_id, None));
+
+        // None of it reaches the document
+        assert_eq!(sync_manager.rga("notes").unwrap().text(), "");
+
+        sync_manager.shutdown().await;
+        timeout(Duration::from_secs(5), server.stopped())
+            .await
+            .expect("Server didn't shut down in time");
+    }
+
+    #[tokio::test]
+    async fn test_sync_manager_enforces_roles() {
+        let authenticator = StaticAuthenticator::new()
+            .with_token("alice-token", "alice")
+            .with_token("victor-token", "victor")
+            .with_role("alice", "notes", Role::Editor)
+            .with_role("victor", "notes", Role::Viewer);
+        let sync_manager = SyncManager::new().with_authenticator(Arc::new(authenticator));
+        let mut server = sync_manager.start_server("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/notes", server.local_addr());
+
+        // The handshake fails without a known token
+        for url in [url.clone(), format!("{}?access_token=nope", url)] {
+            match connect_async(url).await {
+                Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+                    assert_eq!(response.status(), StatusCode::UNAUTHORIZED)
+                }
+                other => panic!("Expected a 401, got {:?}", other.map(|_| ())),
+            }
+        }
+
+        // A bearer header and the query parameter both work
+        let mut request = url.as_str().into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert(AUTHORIZATION, "Bearer alice-token".parse().unwrap());
+        let mut alice = connect_request(request).await;
+        expect_snapshot(&mut alice).await;
+        let mut victor = connect(&format!("{}?access_token=victor-token", url)).await;
+        expect_snapshot(&mut victor).await;
+
+        // Viewers' edits are refused rather than relayed
+        let mut rga = RGA::new();
+        send(&mut victor, &crdt_op(&rga.insert(0, 'v'))).await;
+        match next_envelope(&mut victor).await {
+            Envelope::Error { code, .. } => assert_eq!(code, ErrorCode::PermissionDenied),
+            other => panic!("Expected an error, got {:?}", other),
+        }
+        let op = rga.insert(0, 'a');
+        send(&mut alice, &crdt_op(&op)).await;
+        assert_eq!(expect_op(&mut victor).await, Edit::Crdt(op));
+        assert!(matches!(
+            next_envelope(&mut alice).await,
+            Envelope::Ack { .. }
+        ));
+        assert_eq!(sync_manager.rga("notes").unwrap().text(), "a");
+
+        // Viewers still show up, but only as themselves
+        let mut spoofed = awareness("alice", 0);
+        spoofed.name = "Victor".to_string();
+        let presence = Envelope::Presence {
+            client: None,
+            state: Some(spoofed),
+        };
+        send(&mut victor, &presence).await;
+        let (_, state) = expect_presence(&mut alice).await;
+        assert_eq!(state.unwrap().user_id, "victor");
+
+        // Documents without a grant stay closed
+        let join = Envelope::Join {
+            document: "secret".to_string(),
+            revision: None,
+        };
+        send(&mut alice, &join).await;
+        match next_envelope(&mut alice).await {
+            Envelope::Error { code, .. } => assert_eq!(code, ErrorCode::PermissionDenied),
+            other => panic!("Expected an error, got {:?}", other),
+        }
+        assert_eq!(sync_manager.documents(), vec!["notes"]);
+
+        sync_manager.shutdown().await;
+        timeout(Duration::from_secs(5), server.stopped())
+            .await
+            .expect("Server didn't shut down in time");
+    }
+
+    #[tokio::test]
+    async fn test_sync_manager_rooms() {
+        let sync_manager = SyncManager::new();
+        let mut server = sync_manager.start_server("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut notes = connect(&format!("ws://{}/notes", addr)).await;
+        let mut lobby = connect(&format!("ws://{}", addr)).await;
+        expect_snapshot(&mut notes).await;
+        expect_snapshot(&mut lobby).await;
+        let mut documents = sync_manager.documents();
+        documents.sort();
+        assert_eq!(documents, vec![DEFAULT_DOCUMENT, "notes"]);
+
+        let op = Operation::Insert {
+            index: 0,
+            value: 'a',
+            id: ElementId::new(1, 1),
+            origin: None,
+        };
+        send(&mut notes, &crdt_op(&op)).await;
+        assert!(matches!(
+            next_envelope(&mut notes).await,
+            Envelope::Ack { .. }
+        ));
+        assert!(timeout(Duration::from_millis(200), lobby.next())
+            .await
+            .is_err());
+
+        // Joining moves the lobby connection into the notes room, leaving the
+        // default room without clients
+        let join = Envelope::Join {
+            document: "notes".to_string(),
+            revision: None,
+        };
+        send(&mut lobby, &join).await;
+        assert_eq!(expect_snapshot(&mut lobby).await.text(), "a");
+        assert_eq!(sync_manager.documents(), vec!["notes"]);
+        send(&mut notes, &crdt_op(&op)).await;
+        assert_eq!(expect_op(&mut lobby).await, Edit::Crdt(op.clone()));
+
+        notes.close(None).await.unwrap();
+        lobby.close(None).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(sync_manager.documents().is_empty());
+
+        // Without a store the document outlives its clients, so one coming
+        // back can resend what it never got acked
+        let mut notes = connect(&format!("ws://{}/notes", addr)).await;
+        assert_eq!(expect_snapshot(&mut notes).await.text(), "a");
+        send(&mut notes, &crdt_op(&op)).await;
+        assert!(matches!(
+            next_envelope(&mut notes).await,
+            Envelope::Ack { .. }
+        ));
+
+        sync_manager.shutdown().await;
+        timeout(Duration::from_secs(5), server.stopped())
+            .await
+            .expect("Server didn't shut down in time");
+    }
+
+    #[tokio::test]
+    async fn test_sync_manager_restores_documents_from_store() {
+        let dir = std::env::temp_dir().join(crate::utils::generate_unique_id());
+        let store: Arc<dyn DocumentStore> = Arc::new(crate::store::FileStore::open(&dir).unwrap());
+        let sync_manager = SyncManager::new()
+            .with_store(store.clone())
+            .with_snapshot_interval(2);
+        let mut server = sync_manager.start_server("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut ws_stream = connect(&format!("ws://{}/notes", addr)).await;
+        expect_snapshot(&mut ws_stream).await;
+        let mut rga = RGA::new();
+        let mut ops = vec![rga.insert_text(0, "hello"), rga.delete(0)];
+        for (i, c) in " world".chars().enumerate() {
+            ops.push(rga.insert(4 + i, c));
+        }
+        for op in ops {
+            send(&mut ws_stream, &crdt_op(&op)).await;
+            next_envelope(&mut ws_stream).await;
+        }
+        ws_stream.close(None).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(sync_manager.documents().is_empty());
+        sync_manager.shutdown().await;
+        timeout(Duration::from_secs(5), server.stopped())
+            .await
+            .expect("Server didn't shut down in time");
+
+        // A fresh server over the same store picks up where it left off
+        let sync_manager = SyncManager::new().with_store(store.clone());
+        let mut server = sync_manager.start_server("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut ws_stream = connect(&format!("ws://{}/notes", addr)).await;
+        let snapshot = expect_snapshot(&mut ws_stream).await;
+        assert_eq!(snapshot.text(), "ello world");
+        assert_eq!(snapshot.tombstone_count(), 0);
+        assert_eq!(snapshot.elements.len(), 10);
+
+        let mut restored = snapshot;
+        restored.replica_id = rga.replica_id;
+        send(&mut ws_stream, &crdt_op(&restored.insert(0, 'H'))).await;
+        next_envelope(&mut ws_stream).await;
+        assert_eq!(sync_manager.rga("notes").unwrap().text(), "Hello world");
+
+        sync_manager.shutdown().await;
+        timeout(Duration::from_secs(5), server.stopped())
+            .await
+            .expect("Server didn't shut down in time");
+
+        // OT documents can't be stored
+        let ot = SyncManager::with_mode(SyncMode::Ot).with_store(store);
+        assert!(matches!(
+            ot.start_server("127.0.0.1:0").await,
+            Err(CollaboriError::UnsupportedConfig(_))
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sync_manager_collects_tombstones_every_client_has_seen() {
+        let sync_manager = SyncManager::new().with_snapshot_interval(1);
+        let mut server = sync_manager.start_server("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut alice = connect(&format!("ws://{}/notes", addr)).await;
+        let mut bob = connect(&format!("ws://{}/notes", addr)).await;
+        let mut rga1 = expect_snapshot(&mut alice).await;
+        let mut rga2 = expect_snapshot(&mut bob).await;
+        rga1.replica_id = 1;
+        rga2.replica_id = 2;
+        let typed = rga1.insert_text(0, "xyz");
+        send(&mut alice, &crdt_op(&typed)).await;
+        next_envelope(&mut alice).await;
+        assert!(matches!(next_envelope(&mut bob).await, Envelope::Op { .. }));
+        rga2.apply(&typed);
+
+        // Bob types after 'y' before Alice's deletion of it reaches him
+        send(&mut alice, &crdt_op(&rga1.delete(1))).await;
+        send(&mut bob, &crdt_op(&rga2.insert(2, 'Q'))).await;
+        next_envelope(&mut alice).await;
+        for _ in 0..2 {
+            assert!(matches!(
+                next_envelope(&mut bob).await,
+                Envelope::Op { .. } | Envelope::Ack { .. }
+            ));
+        }
+        assert!(matches!(
+            next_envelope(&mut alice).await,
+            Envelope::Op { .. }
+        ));
+        assert_eq!(sync_manager.rga("notes").unwrap().text(), "xQz");
+
+        // Once both have been sent everything, 'y' goes
+        for c in "!?".chars() {
+            let op = rga1.insert(2, c);
+            send(&mut alice, &crdt_op(&op)).await;
+            next_envelope(&mut alice).await;
+            next_envelope(&mut bob).await;
+        }
+        let rga = sync_manager.rga("notes").unwrap();
+        assert_eq!(rga.text(), "xQz?!");
+        assert_eq!(rga.tombstone_count(), 0);
+
+        sync_manager.shutdown().await;
+        timeout(Duration::from_secs(5), server.stopped())
+            .await
+            .expect("Server didn't shut down in time");
+    }
+
+    /// Reads until the server closes the connection, returning the close code
+    async fn expect_close(ws_stream: &mut Client) -> CloseCode {
+        loop {
+            match timeout(Duration::from_secs(1), ws_stream.next()).await {
+                Ok(Some(Ok(Message::Close(Some(frame))))) => return frame.code,
+                Ok(Some(Ok(_))) => continue,
+                other => panic!("Connection was not closed: {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_manager_applies_connection_limits() {
+        let sync_manager = SyncManager::with_config(ServerConfig {
+            max_connections: Some(1),
+            max_message_size: Some(1024),
+            idle_timeout: Some(Duration::from_millis(300)),
+            allowed_origins: vec!["https://docs.example.com".to_string()],
+            ..ServerConfig::default()
+        });
+        let mut server = sync_manager.start_server("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", server.local_addr());
+        let request = |origin: Option<&str>| {
+            let mut request = url.as_str().into_client_request().unwrap();
+            if let Some(origin) = origin {
+                request
+                    .headers_mut()
+                    .insert(ORIGIN, origin.parse().unwrap());
+            }
+            request
+        };
+        let rejected = |result: Result<_, WsError>| match result {
+            Err(WsError::Http(response)) => response.status(),
+            other => panic!("Expected the handshake to fail, got {:?}", other),
+        };
+
+        // Browsers on other sites are turned away
+        let status = rejected(connect_async(request(Some("https://evil.example.com"))).await);
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // Only one connection is served at a time
+        let mut alice = connect_request(request(Some("https://docs.example.com"))).await;
+        expect_snapshot(&mut alice).await;
+        let status = rejected(connect_async(request(Some("https://docs.example.com"))).await);
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        // Oversized messages close the connection
+        let op = Operation::InsertText {
+            index: 0,
+            text: "a".repeat(2000),
+            id: ElementId::new(1, 1),
+            origin: None,
+        };
+        send(&mut alice, &crdt_op(&op)).await;
+        assert_eq!(expect_close(&mut alice).await, CloseCode::Size);
+
+        // And so does silence
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut bob = connect_request(request(Some("https://docs.example.com"))).await;
+        expect_snapshot(&mut bob).await;
+        assert_eq!(expect_close(&mut bob).await, CloseCode::Policy);
+
+        sync_manager.shutdown().await;
+        timeout(Duration::from_secs(5), server.stopped())
             .await
             .expect("Server didn't shut down in time");
     }
+
+    #[tokio::test]
+    async fn test_sync_manager_closes_unresponsive_connections() {
+        let sync_manager = SyncManager::with_config(ServerConfig {
+            ping_interval: Some(Duration::from_millis(50)),
+            max_missed_pings: 2,
+            handshake_timeout: Some(Duration::from_millis(100)),
+            ..ServerConfig::default()
+        });
+        let mut server = sync_manager.start_server("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr();
+        let url = format!("ws://{}", addr);
+
+        // A client reading its socket answers the ping frames on its own and
+        // stays connected
+        let mut alice = connect(&url).await;
+        expect_snapshot(&mut alice).await;
+        let mut pings = 0;
+        while pings < 4 {
+            match timeout(Duration::from_secs(1), alice.next()).await {
+                Ok(Some(Ok(Message::Ping(_)))) => pings += 1,
+                other => panic!("Expected a ping, got {:?}", other),
+            }
+        }
+
+        // One that doesn't is closed after missing two
+        let mut bob = connect(&url).await;
+        expect_snapshot(&mut bob).await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        loop {
+            // Reading answers the queued pings, which may fail on the closed
+            // socket before the close frame is read
+            match timeout(Duration::from_secs(1), bob.next()).await {
+                Ok(Some(Ok(Message::Close(frame)))) => {
+                    assert_eq!(frame.unwrap().code, CloseCode::Policy);
+                    break;
+                }
+                Ok(Some(Ok(_))) => continue,
+                Ok(Some(Err(_)) | None) => break,
+                Err(_) => panic!("Connection was not closed"),
+            }
+        }
+
+        // Connections that never finish their handshake are dropped
+        let (mut carol, _) = connect_async(url.as_str()).await.unwrap();
+        assert_eq!(expect_close(&mut carol).await, CloseCode::Policy);
+        let mut stuck = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0; 1];
+        let read = timeout(Duration::from_secs(1), stuck.read(&mut buf)).await;
+        assert!(matches!(read, Ok(Ok(0))), "got {:?}", read);
+
+        sync_manager.shutdown().await;
+        timeout(Duration::from_secs(5), server.stopped())
+            .await
+            .expect("Server didn't shut down in time");
+    }
+
+    #[tokio::test]
+    async fn test_sync_manager_reaps_backed_up_connections() {
+        let sync_manager = SyncManager::with_config(ServerConfig {
+            connection_capacity: 1,
+            idle_timeout: Some(Duration::from_secs(2)),
+            ping_interval: None,
+            ..ServerConfig::default()
+        });
+        let mut server = sync_manager.start_server("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // A client that stops reading while another floods the room with
+        // presence fills its socket and then its queue
+        let mut stalled = connect(&format!("ws://{}/notes", addr)).await;
+        expect_snapshot(&mut stalled).await;
+        let mut chatty = connect(&format!("ws://{}/notes", addr)).await;
+        expect_snapshot(&mut chatty).await;
+        let name = "x".repeat(1 << 18);
+        for cursor in 0..64 {
+            let mut state = awareness("chatty", cursor);
+            state.name = name.clone();
+            let presence = Envelope::Presence {
+                client: None,
+                state: Some(state),
+            };
+            send(&mut chatty, &presence).await;
+        }
+        chatty.close(None).await.unwrap();
+
+        // It is still dropped once it goes idle
+        timeout(Duration::from_secs(5), async {
+            while !sync_manager.documents().is_empty() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("The stalled connection was not dropped");
+        drop(stalled);
+
+        sync_manager.shutdown().await;
+        timeout(Duration::from_secs(5), server.stopped())
+            .await
+            .expect("Server didn't shut down in time");
+    }
+
+    /// Remembers which documents were snapshotted
+    #[derive(Debug, Default)]
+    struct SnapshotLog(Mutex<Vec<String>>);
+
+    impl DocumentStore for SnapshotLog {
+        fn append(&self, _: &str, _: &Operation) -> Result<(), CollaboriError> {
+            Ok(())
+        }
+
+        fn snapshot(&self, document: &str, _: &RGA) -> Result<(), CollaboriError> {
+            self.0.lock().unwrap().push(document.to_string());
+            Ok(())
+        }
+
+        fn load(&self, _: &str) -> Result<Option<RGA>, CollaboriError> {
+            Ok(None)
+        }
+    }
+
+    /// Records the operations appended to it until its disk fills up
+    #[derive(Debug, Default)]
+    struct OperationLog {
+        ops: Mutex<Vec<Operation>>,
+        full: AtomicBool,
+    }
+
+    impl DocumentStore for OperationLog {
+        fn append(&self, _: &str, op: &Operation) -> Result<(), CollaboriError> {
+            if self.full.load(Ordering::Relaxed) {
+                return Err(std::io::Error::other("disk full").into());
+            }
+            self.ops.lock().unwrap().push(op.clone());
+            Ok(())
+        }
+
+        fn snapshot(&self, _: &str, _: &RGA) -> Result<(), CollaboriError> {
+            Ok(())
+        }
+
+        fn load(&self, _: &str) -> Result<Option<RGA>, CollaboriError> {
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_manager_acks_stored_operations() {
+        let store = Arc::new(OperationLog::default());
+        let sync_manager = SyncManager::new().with_store(store.clone());
+        let mut server = sync_manager.start_server("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut alice = connect(&format!("ws://{}/notes", addr)).await;
+        let mut bob = connect(&format!("ws://{}/notes", addr)).await;
+        let mut rga = expect_snapshot(&mut alice).await;
+        expect_snapshot(&mut bob).await;
+        let op = rga.insert(0, 'a');
+        send(&mut alice, &crdt_op(&op)).await;
+        assert!(matches!(
+            next_envelope(&mut alice).await,
+            Envelope::Ack { id: Some(id), .. } if id == op.last_id()
+        ));
+        assert_eq!(*store.ops.lock().unwrap(), vec![op]);
+
+        // Others still get an operation the store lost, but its sender
+        // isn't told it is safe
+        store.full.store(true, Ordering::Relaxed);
+        let op = rga.insert(1, 'b');
+        send(&mut alice, &crdt_op(&op)).await;
+        match next_envelope(&mut alice).await {
+            Envelope::Error { code, message } => {
+                assert_eq!(code, ErrorCode::StorageFailed);
+                assert!(message.contains("disk full"));
+            }
+            other => panic!("Expected an error, got {:?}", other),
+        }
+        next_envelope(&mut bob).await;
+        assert_eq!(expect_op(&mut bob).await, Edit::Crdt(op));
+
+        sync_manager.shutdown().await;
+        timeout(Duration::from_secs(5), server.stopped())
+            .await
+            .expect("Server didn't shut down in time");
+    }
+
+    #[tokio::test]
+    async fn test_sync_manager_shuts_down_gracefully() {
+        let store = Arc::new(SnapshotLog::default());
+        let sync_manager = SyncManager::with_config(ServerConfig {
+            store: Some(store.clone()),
+            shutdown_timeout: Duration::from_millis(200),
+            ..ServerConfig::default()
+        });
+        let mut server = sync_manager.start_server("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr();
+
+        let mut alice = connect(&format!("ws://{}/notes", addr)).await;
+        expect_snapshot(&mut alice).await;
+        let op = RGA::new().insert(0, 'a');
+        send(&mut alice, &crdt_op(&op)).await;
+        next_envelope(&mut alice).await;
+
+        // A connection that never finishes its handshake can't hold it up
+        let _stuck = TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        server.shutdown();
+        assert_eq!(expect_close(&mut alice).await, CloseCode::Away);
+        timeout(Duration::from_secs(5), server.stopped())
+            .await
+            .expect("Server didn't shut down in time");
+
+        // Documents are saved and nobody else gets in
+        assert_eq!(*store.0.lock().unwrap(), vec!["notes".to_string()]);
+        assert!(sync_manager.documents().is_empty());
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    /// Takes a while to append anything
+    #[derive(Debug)]
+    struct SlowDisk(Duration);
+
+    impl DocumentStore for SlowDisk {
+        fn append(&self, _: &str, _: &Operation) -> Result<(), CollaboriError> {
+            std::thread::sleep(self.0);
+            Ok(())
+        }
+
+        fn snapshot(&self, _: &str, _: &RGA) -> Result<(), CollaboriError> {
+            Ok(())
+        }
+
+        fn load(&self, _: &str) -> Result<Option<RGA>, CollaboriError> {
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_manager_drops_aborted_connections() {
+        let sync_manager = SyncManager::with_config(ServerConfig {
+            store: Some(Arc::new(SlowDisk(Duration::from_secs(2)))),
+            shutdown_timeout: Duration::from_millis(200),
+            ..ServerConfig::default()
+        });
+        let mut server = sync_manager.start_server("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // The connection is still waiting on the store at the deadline
+        let mut alice = connect(&format!("ws://{}/notes", addr)).await;
+        expect_snapshot(&mut alice).await;
+        send(&mut alice, &crdt_op(&RGA::new().insert(0, 'a'))).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        server.shutdown();
+
+        // Nothing it spawned keeps the socket open once it is aborted, while
+        // the server still waits for the store
+        match timeout(Duration::from_secs(1), alice.next()).await {
+            Ok(None | Some(Err(_))) => {}
+            other => panic!("Connection was not dropped: {:?}", other),
+        }
+        timeout(Duration::from_secs(5), server.stopped())
+            .await
+            .expect("Server didn't shut down in time");
+    }
+
+    /// Subscribes connection 7 to `manager`'s default room from `revision`,
+    /// applies `edits` before it reads anything, then returns what it is sent
+    async fn forward_lagging(
+        manager: SyncManager,
+        revision: Option<usize>,
+        edits: Vec<(u64, Edit)>,
+    ) -> Vec<Message> {
+        let shared = manager.shared();
+        let room = shared.join(DEFAULT_DOCUMENT).await.unwrap();
+        let subscription = room.subscribe(7, revision);
+        for (origin, edit) in edits {
+            room.receive(origin, edit).await.unwrap();
+        }
+        let (outgoing, mut outgoing_rx) = mpsc::channel(1);
+        let policy = manager.config.lag_policy;
+        let forward = tokio::spawn(forward_updates(
+            room,
+            subscription,
+            7,
+            Codec::Json,
+            outgoing,
+            policy,
+        ));
+        let mut sent = Vec::new();
+        while let Ok(Some(msg)) = timeout(Duration::from_millis(100), outgoing_rx.recv()).await {
+            sent.push(msg);
+        }
+        forward.abort();
+        sent
+    }
+
+    fn decode(msg: &Message) -> Envelope {
+        Codec::decode(msg).unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_sync_manager_handles_lagging_connections() {
+        let edits: Vec<(u64, Edit)> = "abcde"
+            .chars()
+            .enumerate()
+            .map(|(i, value)| {
+                let op = Operation::Insert {
+                    index: i,
+                    value,
+                    id: ElementId::new(1, i as u64 + 1),
+                    origin: (i > 0).then(|| ElementId::new(1, i as u64)),
+                };
+                (1, Edit::Crdt(op))
+            })
+            .collect();
+
+        // A connection that missed updates gets the whole document again
+        let manager = SyncManager::new().with_room_capacity(2);
+        let sent = forward_lagging(manager, None, edits.clone()).await;
+        assert_eq!(sent.len(), 2);
+        match decode(&sent[1]) {
+            Envelope::Snapshot {
+                state: DocumentSnapshot::Crdt(rga),
+                ..
+            } => assert_eq!(rga.to_string(), "abcde"),
+            other => panic!("Expected a snapshot, got {:?}", other),
+        }
+
+        // Or is told to come back later
+        let manager = SyncManager::new()
+            .with_room_capacity(2)
+            .with_lag_policy(LagPolicy::Disconnect);
+        let sent = forward_lagging(manager, None, edits).await;
+        assert_eq!(sent.len(), 2);
+        match &sent[1] {
+            Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Again),
+            other => panic!("Expected a close frame, got {:?}", other),
+        }
+
+        // OT connections get the revisions they missed, their own as acks
+        let edits = vec![
+            (
+                7,
+                Edit::Text {
+                    revision: 0,
+                    operation: TextOperation::new().insert("a"),
+                },
+            ),
+            (
+                1,
+                Edit::Text {
+                    revision: 1,
+                    operation: TextOperation::new().retain(1).insert("b"),
+                },
+            ),
+            (
+                7,
+                Edit::Text {
+                    revision: 2,
+                    operation: TextOperation::new().retain(2).insert("c"),
+                },
+            ),
+        ];
+        let manager = SyncManager::with_mode(SyncMode::Ot).with_room_capacity(2);
+        let sent = forward_lagging(manager, Some(0), edits).await;
+        let sent: Vec<Envelope> = sent.iter().map(decode).collect();
+        assert_eq!(sent.len(), 3);
+        assert!(matches!(
+            sent[0],
+            Envelope::Ack {
+                id: None,
+                revision: Some(1)
+            }
+        ));
+        match &sent[1] {
+            Envelope::Op { edit } => assert_eq!(
+                *edit,
+                Edit::Text {
+                    revision: 1,
+                    operation: TextOperation::new().retain(1).insert("b"),
+                }
+            ),
+            other => panic!("Expected an operation, got {:?}", other),
+        }
+        assert!(matches!(
+            sent[2],
+            Envelope::Ack {
+                id: None,
+                revision: Some(3)
+            }
+        ));
+    }
 }
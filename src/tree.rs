@@ -0,0 +1,548 @@
+use crate::crdt::Element;
+use crate::data::ElementId;
+use crate::errors::CollaboriError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Index;
+
+const NIL: usize = usize::MAX;
+
+/// Ordered sequence of RGA elements backed by an implicit treap.
+///
+/// Nodes live in an arena and keep parent links plus subtree counts of all
+/// and of visible elements, so positional lookups, visible/raw position
+/// translation, lookups by id, inserts and removals are all logarithmic.
+#[derive(Clone)]
+pub struct ElementTree {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    root: usize,
+    index: HashMap<ElementId, usize>,
+    seed: u64,
+}
+
+#[derive(Clone)]
+struct Node {
+    element: Element,
+    left: usize,
+    right: usize,
+    parent: usize,
+    priority: u64,
+    size: usize,
+    visible: usize,
+}
+
+impl Default for ElementTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ElementTree {
+    /// Creates an empty tree
+    pub fn new() -> Self {
+        ElementTree {
+            nodes: Vec::new(),
+            free: Vec::new(),
+            root: NIL,
+            index: HashMap::new(),
+            seed: 0,
+        }
+    }
+
+    /// Returns the number of elements, tombstones included
+    pub fn len(&self) -> usize {
+        self.size(self.root)
+    }
+
+    /// Returns true if the tree holds no elements
+    pub fn is_empty(&self) -> bool {
+        self.root == NIL
+    }
+
+    /// Returns the number of visible elements
+    pub fn visible_len(&self) -> usize {
+        self.visible(self.root)
+    }
+
+    /// Returns the element at a raw position
+    pub fn get(&self, pos: usize) -> Option<&Element> {
+        self.node_at(pos).map(|n| &self.nodes[n].element)
+    }
+
+    /// Returns the element with the given id
+    pub fn get_by_id(&self, id: &ElementId) -> Option<&Element> {
+        self.index.get(id).map(|&n| &self.nodes[n].element)
+    }
+
+    /// Returns true if an element with the given id is stored
+    pub fn contains(&self, id: &ElementId) -> bool {
+        self.index.contains_key(id)
+    }
+
+    /// Returns the raw position of an element, tombstones included
+    pub fn position(&self, id: &ElementId) -> Option<usize> {
+        let mut node = *self.index.get(id)?;
+        let mut pos = self.size(self.nodes[node].left);
+        while self.nodes[node].parent != NIL {
+            let parent = self.nodes[node].parent;
+            if self.nodes[parent].right == node {
+                pos += self.size(self.nodes[parent].left) + 1;
+            }
+            node = parent;
+        }
+        Some(pos)
+    }
+
+    /// Returns the number of visible elements preceding an element
+    pub fn visible_position(&self, id: &ElementId) -> Option<usize> {
+        let mut node = *self.index.get(id)?;
+        let mut pos = self.visible(self.nodes[node].left);
+        while self.nodes[node].parent != NIL {
+            let parent = self.nodes[node].parent;
+            if self.nodes[parent].right == node {
+                pos += self.visible(self.nodes[parent].left) + self.own_visible(parent);
+            }
+            node = parent;
+        }
+        Some(pos)
+    }
+
+    /// Returns the id of the visible element at a visible position
+    pub fn visible_id(&self, mut index: usize) -> Option<ElementId> {
+        let mut node = self.root;
+        while node != NIL {
+            let left = self.visible(self.nodes[node].left);
+            if index < left {
+                node = self.nodes[node].left;
+                continue;
+            }
+            index -= left;
+            if self.nodes[node].element.visible {
+                if index == 0 {
+                    return Some(self.nodes[node].element.id);
+                }
+                index -= 1;
+            }
+            node = self.nodes[node].right;
+        }
+        None
+    }
+
+    /// Builds a tree from elements in document order, failing on duplicate
+    /// ids or on origins that don't precede their element, as untrusted
+    /// input such as a decoded snapshot may contain
+    pub fn from_elements<I>(elements: I) -> Result<Self, CollaboriError>
+    where
+        I: IntoIterator<Item = Element>,
+    {
+        let mut tree = ElementTree::new();
+        for element in elements {
+            if let Some(origin) = element.origin.filter(|origin| !tree.contains(origin)) {
+                return Err(CollaboriError::DecodeError(format!(
+                    "element {:?} precedes its origin {:?}",
+                    element.id, origin
+                )));
+            }
+            let len = tree.len();
+            tree.try_insert(len, element)?;
+        }
+        Ok(tree)
+    }
+
+    /// Inserts an element at a raw position
+    pub fn insert(&mut self, pos: usize, element: Element) {
+        assert!(
+            !self.index.contains_key(&element.id),
+            "Element ids must be unique"
+        );
+        self.insert_unique(pos, element);
+    }
+
+    /// Inserts an element at a raw position, failing if its id is already
+    /// stored
+    pub fn try_insert(&mut self, pos: usize, element: Element) -> Result<(), CollaboriError> {
+        if self.index.contains_key(&element.id) {
+            return Err(CollaboriError::DecodeError(format!(
+                "duplicate element id {:?}",
+                element.id
+            )));
+        }
+        self.insert_unique(pos, element);
+        Ok(())
+    }
+
+    fn insert_unique(&mut self, pos: usize, element: Element) {
+        assert!(pos <= self.len(), "Index out of bounds");
+        let id = element.id;
+        let node = self.alloc(element);
+        self.index.insert(id, node);
+        let (left, right) = self.split(self.root, pos);
+        let root = self.merge(left, node);
+        self.root = self.merge(root, right);
+        self.nodes[self.root].parent = NIL;
+    }
+
+    /// Marks an element visible or deleted, returning its previous visibility
+    pub fn set_visible(&mut self, id: &ElementId, visible: bool) -> Option<bool> {
+        let mut node = *self.index.get(id)?;
+        let previous = self.nodes[node].element.visible;
+        if previous != visible {
+            self.nodes[node].element.visible = visible;
+            while node != NIL {
+                if visible {
+                    self.nodes[node].visible += 1;
+                } else {
+                    self.nodes[node].visible -= 1;
+                }
+                node = self.nodes[node].parent;
+            }
+        }
+        Some(previous)
+    }
+
+    /// Removes an element, returning it
+    pub fn remove(&mut self, id: &ElementId) -> Option<Element> {
+        let pos = self.position(id)?;
+        let node = self.index.remove(id)?;
+        let (left, rest) = self.split(self.root, pos);
+        let (_, right) = self.split(rest, 1);
+        self.root = self.merge(left, right);
+        if self.root != NIL {
+            self.nodes[self.root].parent = NIL;
+        }
+        self.free.push(node);
+        let placeholder = Element {
+            id: ElementId::default(),
+            origin: None,
+            value: '\0',
+            visible: false,
+        };
+        Some(std::mem::replace(
+            &mut self.nodes[node].element,
+            placeholder,
+        ))
+    }
+
+    /// Iterates over all elements in document order
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            tree: self,
+            node: self.node_at(0).unwrap_or(NIL),
+        }
+    }
+
+    /// Iterates over the elements starting at a raw position
+    pub fn iter_from(&self, pos: usize) -> Iter<'_> {
+        Iter {
+            tree: self,
+            node: self.node_at(pos).unwrap_or(NIL),
+        }
+    }
+
+    fn alloc(&mut self, element: Element) -> usize {
+        // Priorities only need to be well spread, so a splitmix64 sequence
+        // keeps trees deterministic without pulling in a random generator.
+        self.seed = self.seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.seed;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        let visible = usize::from(element.visible);
+        let node = Node {
+            element,
+            left: NIL,
+            right: NIL,
+            parent: NIL,
+            priority: z ^ (z >> 31),
+            size: 1,
+            visible,
+        };
+        match self.free.pop() {
+            Some(slot) => {
+                self.nodes[slot] = node;
+                slot
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    fn node_at(&self, mut pos: usize) -> Option<usize> {
+        let mut node = self.root;
+        while node != NIL {
+            let left = self.size(self.nodes[node].left);
+            match pos.cmp(&left) {
+                std::cmp::Ordering::Less => node = self.nodes[node].left,
+                std::cmp::Ordering::Equal => return Some(node),
+                std::cmp::Ordering::Greater => {
+                    pos -= left + 1;
+                    node = self.nodes[node].right;
+                }
+            }
+        }
+        None
+    }
+
+    fn successor(&self, mut node: usize) -> usize {
+        if self.nodes[node].right != NIL {
+            node = self.nodes[node].right;
+            while self.nodes[node].left != NIL {
+                node = self.nodes[node].left;
+            }
+            return node;
+        }
+        loop {
+            let parent = self.nodes[node].parent;
+            if parent == NIL || self.nodes[parent].left == node {
+                return parent;
+            }
+            node = parent;
+        }
+    }
+
+    /// Splits a subtree into its first `k` elements and the rest
+    fn split(&mut self, node: usize, k: usize) -> (usize, usize) {
+        if node == NIL {
+            return (NIL, NIL);
+        }
+        let left = self.nodes[node].left;
+        let left_size = self.size(left);
+        if k <= left_size {
+            let (a, b) = self.split(left, k);
+            self.nodes[node].left = b;
+            self.update(node);
+            (a, node)
+        } else {
+            let right = self.nodes[node].right;
+            let (a, b) = self.split(right, k - left_size - 1);
+            self.nodes[node].right = a;
+            self.update(node);
+            (node, b)
+        }
+    }
+
+    /// Concatenates two subtrees
+    fn merge(&mut self, a: usize, b: usize) -> usize {
+        if a == NIL {
+            return b;
+        }
+        if b == NIL {
+            return a;
+        }
+        if self.nodes[a].priority > self.nodes[b].priority {
+            let right = self.nodes[a].right;
+            self.nodes[a].right = self.merge(right, b);
+            self.update(a);
+            a
+        } else {
+            let left = self.nodes[b].left;
+            self.nodes[b].left = self.merge(a, left);
+            self.update(b);
+            b
+        }
+    }
+
+    /// Recomputes a node's counts and re-parents its children
+    fn update(&mut self, node: usize) {
+        let (left, right) = (self.nodes[node].left, self.nodes[node].right);
+        self.nodes[node].size = 1 + self.size(left) + self.size(right);
+        self.nodes[node].visible =
+            self.own_visible(node) + self.visible(left) + self.visible(right);
+        if left != NIL {
+            self.nodes[left].parent = node;
+        }
+        if right != NIL {
+            self.nodes[right].parent = node;
+        }
+    }
+
+    fn size(&self, node: usize) -> usize {
+        if node == NIL {
+            0
+        } else {
+            self.nodes[node].size
+        }
+    }
+
+    fn visible(&self, node: usize) -> usize {
+        if node == NIL {
+            0
+        } else {
+            self.nodes[node].visible
+        }
+    }
+
+    fn own_visible(&self, node: usize) -> usize {
+        usize::from(self.nodes[node].element.visible)
+    }
+}
+
+/// In-order iterator over an `ElementTree`
+pub struct Iter<'a> {
+    tree: &'a ElementTree,
+    node: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a Element;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.node == NIL {
+            return None;
+        }
+        let element = &self.tree.nodes[self.node].element;
+        self.node = self.tree.successor(self.node);
+        Some(element)
+    }
+}
+
+impl<'a> IntoIterator for &'a ElementTree {
+    type Item = &'a Element;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl FromIterator<Element> for ElementTree {
+    fn from_iter<I: IntoIterator<Item = Element>>(iter: I) -> Self {
+        let mut tree = ElementTree::new();
+        for element in iter {
+            let len = tree.len();
+            tree.insert(len, element);
+        }
+        tree
+    }
+}
+
+impl Index<usize> for ElementTree {
+    type Output = Element;
+
+    fn index(&self, pos: usize) -> &Element {
+        self.get(pos).expect("Index out of bounds")
+    }
+}
+
+impl fmt::Debug for ElementTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl Serialize for ElementTree {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for ElementTree {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let elements = Vec::<Element>::deserialize(deserializer)?;
+        ElementTree::from_elements(elements).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn element(counter: u64, value: char, visible: bool) -> Element {
+        Element {
+            id: ElementId::new(1, counter),
+            origin: None,
+            value,
+            visible,
+        }
+    }
+
+    fn values(tree: &ElementTree) -> String {
+        tree.iter().map(|e| e.value).collect()
+    }
+
+    #[test]
+    fn test_insert_and_positions() {
+        let mut tree = ElementTree::new();
+        tree.insert(0, element(1, 'b', true));
+        tree.insert(0, element(2, 'a', true));
+        tree.insert(2, element(3, 'd', true));
+        tree.insert(2, element(4, 'c', false));
+
+        assert_eq!(values(&tree), "abcd");
+        assert_eq!(tree.len(), 4);
+        assert_eq!(tree.visible_len(), 3);
+        assert_eq!(tree[2].value, 'c');
+        assert_eq!(tree.position(&ElementId::new(1, 3)), Some(3));
+        assert_eq!(tree.visible_position(&ElementId::new(1, 3)), Some(2));
+        assert_eq!(tree.visible_id(2), Some(ElementId::new(1, 3)));
+        assert_eq!(tree.visible_id(3), None);
+    }
+
+    #[test]
+    fn test_set_visible_and_remove() {
+        let mut tree: ElementTree = "abc"
+            .chars()
+            .enumerate()
+            .map(|(i, c)| element(i as u64 + 1, c, true))
+            .collect();
+
+        assert_eq!(tree.set_visible(&ElementId::new(1, 2), false), Some(true));
+        assert_eq!(tree.visible_len(), 2);
+        assert_eq!(tree.visible_id(1), Some(ElementId::new(1, 3)));
+
+        let removed = tree.remove(&ElementId::new(1, 2)).unwrap();
+        assert_eq!(removed.value, 'b');
+        assert_eq!(values(&tree), "ac");
+        assert!(!tree.contains(&ElementId::new(1, 2)));
+
+        // Freed slots are reused without disturbing the order
+        tree.insert(1, element(4, 'x', true));
+        assert_eq!(values(&tree), "axc");
+        assert_eq!(tree.position(&ElementId::new(1, 3)), Some(2));
+    }
+
+    #[test]
+    fn test_matches_vec_model() {
+        let mut tree = ElementTree::new();
+        let mut model: Vec<char> = Vec::new();
+        let mut state = 7u64;
+        for counter in 1..2000u64 {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+            let pos = (state >> 33) as usize % (model.len() + 1);
+            let value = char::from(b'a' + (counter % 26) as u8);
+            tree.insert(pos, element(counter, value, true));
+            model.insert(pos, value);
+        }
+        assert_eq!(values(&tree), model.iter().collect::<String>());
+        for (pos, e) in tree.iter().enumerate().step_by(97) {
+            assert_eq!(tree.position(&e.id), Some(pos));
+        }
+    }
+
+    #[test]
+    fn test_serde_roundtrip() {
+        let tree: ElementTree = "hi"
+            .chars()
+            .enumerate()
+            .map(|(i, c)| element(i as u64 + 1, c, i == 0))
+            .collect();
+        let json = serde_json::to_string(&tree).unwrap();
+        let restored: ElementTree = serde_json::from_str(&json).unwrap();
+        assert_eq!(values(&restored), "hi");
+        assert_eq!(restored.visible_len(), 1);
+
+        let duplicated = serde_json::to_string(&[element(1, 'a', true), element(1, 'b', true)]);
+        assert!(serde_json::from_str::<ElementTree>(&duplicated.unwrap()).is_err());
+
+        // Origins must come before the elements anchored on them
+        let mut anchored = element(2, 'b', true);
+        anchored.origin = Some(ElementId::new(1, 1));
+        let late_origin = serde_json::to_string(&[anchored.clone(), element(1, 'a', true)]);
+        assert!(serde_json::from_str::<ElementTree>(&late_origin.unwrap()).is_err());
+        let missing_origin = serde_json::to_string(&[anchored]);
+        assert!(serde_json::from_str::<ElementTree>(&missing_origin.unwrap()).is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use std::time::Duration;
 use uuid::Uuid;
 
 /// Generates a unique identifier
@@ -5,6 +6,21 @@ pub fn generate_unique_id() -> String {
     Uuid::new_v4().to_string()
 }
 
+/// Generates a random replica identifier
+pub fn generate_replica_id() -> u64 {
+    Uuid::new_v4().as_u64_pair().0
+}
+
+/// Returns a random duration of at most `max`, used to spread out retries
+pub fn jitter(max: Duration) -> Duration {
+    let nanos = max.as_nanos().min(u64::MAX as u128) as u64;
+    Duration::from_nanos(
+        generate_replica_id()
+            .checked_rem(nanos.saturating_add(1))
+            .unwrap_or(0),
+    )
+}
+
 /// Gets the current timestamp in milliseconds
 pub fn current_timestamp() -> u128 {
     use std::time::SystemTime;
@@ -6,14 +6,40 @@ use tokio::time::{sleep, timeout, Duration};
 use tokio_tungstenite::connect_async;
 use url::Url;
 
+#[test]
+fn test_replicas_exchange_operations() {
+    let mut client1 = RGA::new();
+    let mut client2 = RGA::new();
+
+    // Both clients type concurrently at the head of the document
+    let ops1 = [client1.insert(0, 'H'), client1.insert(1, 'i')];
+    let ops2 = [client2.insert(0, 'W'), client2.insert(1, 'o')];
+
+    // Deliver each side's operations to the other, the second one twice
+    // and in reverse order to simulate duplicates and reordering
+    for op in &ops2 {
+        client1.apply(op);
+    }
+    for op in ops1.iter().rev().chain(ops1.iter()) {
+        client2.apply(op);
+    }
+
+    let client1_content = client1.text();
+    let client2_content = client2.text();
+
+    assert_eq!(
+        client1_content, client2_content,
+        "Client documents do not match"
+    );
+    assert!(client1_content == "HiWo" || client1_content == "WoHi");
+}
+
 #[tokio::test]
 async fn test_real_time_collaboration() {
-    // Define the address for the WebSocket server
-    let addr = "127.0.0.1:9001";
-
-    // Initialize and start the WebSocket server
+    // Initialize and start the WebSocket server on a free port
     let sync_manager = SyncManager::new();
-    let mut shutdown_rx = sync_manager.start_server(addr).await;
+    let mut server = sync_manager.start_server("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr();
 
     // Give the server a moment to start
     sleep(Duration::from_millis(100)).await;
@@ -33,21 +59,21 @@ async fn test_real_time_collaboration() {
     let transformed_op2 = OT::transform(&op2, &op1); // Transform op2 against op1
 
     // Apply transformed_op2 to client1 (Client 1 receives Client 2's operation)
-    match transformed_op2 {
-        Operation::Insert { index, value, .. } => {
-            client1.insert(index, value);
+    match transformed_op2.as_slice() {
+        [Operation::Insert { index, value, .. }] => {
+            client1.insert(*index, *value);
         }
-        Operation::Delete { .. } => {
+        _ => {
             panic!("Expected Insert operation for transformed_op2");
         }
     }
 
     // Apply transformed_op1 to client2 (Client 2 receives Client 1's operation)
-    match transformed_op1 {
-        Operation::Insert { index, value, .. } => {
-            client2.insert(index, value);
+    match transformed_op1.as_slice() {
+        [Operation::Insert { index, value, .. }] => {
+            client2.insert(*index, *value);
         }
-        Operation::Delete { .. } => {
+        _ => {
             panic!("Expected Insert operation for transformed_op1");
         }
     }
@@ -121,7 +147,7 @@ async fn test_real_time_collaboration() {
     sync_manager.shutdown().await;
 
     // Wait for the server to shut down
-    timeout(Duration::from_secs(5), shutdown_rx.recv())
+    timeout(Duration::from_secs(5), server.stopped())
         .await
         .expect("Server didn't shut down in time");
 }
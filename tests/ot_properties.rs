@@ -0,0 +1,133 @@
+use collabori::data::{ElementId, Operation};
+use collabori::ot::{TextOperation, OT};
+use proptest::prelude::*;
+
+/// Id of the character at `index` in a generated document
+fn doc_id(index: usize) -> ElementId {
+    ElementId::new(9, index as u64 + 1)
+}
+
+/// Applies index-based operations to a plain string, checking that range
+/// deletions carrying ids remove exactly those characters
+fn apply(doc: &str, ops: &[Operation]) -> String {
+    let mut chars: Vec<(char, Option<ElementId>)> = doc
+        .chars()
+        .enumerate()
+        .map(|(i, c)| (c, Some(doc_id(i))))
+        .collect();
+    for op in ops {
+        match op {
+            Operation::Insert { index, value, .. } => chars.insert(*index, (*value, None)),
+            Operation::InsertText { index, text, .. } => {
+                chars.splice(*index..*index, text.chars().map(|c| (c, None)));
+            }
+            Operation::Delete { index, .. } => {
+                chars.remove(*index);
+            }
+            Operation::DeleteRange {
+                index, len, ids, ..
+            } => {
+                let removed: Vec<_> = chars.drain(*index..*index + len).collect();
+                if !ids.is_empty() {
+                    let removed: Vec<_> = removed.iter().map(|(_, id)| *id).collect();
+                    let expected: Vec<_> = ids.iter().copied().map(Some).collect();
+                    assert_eq!(removed, expected, "{:?} deleted the wrong characters", op);
+                }
+            }
+            Operation::Noop { .. } => {}
+        }
+    }
+    chars.into_iter().map(|(c, _)| c).collect()
+}
+
+/// Generates any operation valid on a document of `len` characters. Range
+/// deletions carry the ids of the characters they delete, or none
+fn operation(len: usize, replica: u64) -> BoxedStrategy<Operation> {
+    let id = ElementId::new(replica, 1);
+    let insert = (0..=len, proptest::char::range('a', 'z')).prop_map(move |(index, value)| {
+        Operation::Insert {
+            index,
+            value,
+            id,
+            origin: None,
+        }
+    });
+    let insert_text =
+        (0..=len, "[a-z]{1,4}").prop_map(move |(index, text)| Operation::InsertText {
+            index,
+            text,
+            id,
+            origin: None,
+        });
+    if len == 0 {
+        return prop_oneof![insert, insert_text].boxed();
+    }
+    let delete = (0..len).prop_map(move |index| Operation::Delete {
+        index,
+        id,
+        stamp: id,
+    });
+    let delete_range = (0..len)
+        .prop_flat_map(move |index| (Just(index), 1..=len - index, any::<bool>()))
+        .prop_map(move |(index, len, with_ids)| Operation::DeleteRange {
+            index,
+            len,
+            ids: match with_ids {
+                true => (index..index + len).map(doc_id).collect(),
+                false => Vec::new(),
+            },
+            stamp: id,
+        });
+    prop_oneof![insert, insert_text, delete, delete_range].boxed()
+}
+
+/// Generates a document together with two concurrent operations on it
+fn concurrent_pair() -> impl Strategy<Value = (String, Operation, Operation)> {
+    "[A-Z]{0,12}".prop_flat_map(|doc| {
+        let len = doc.chars().count();
+        (Just(doc), operation(len, 1), operation(len, 2))
+    })
+}
+
+/// Generates a compound operation valid on `doc`
+fn text_operation(doc: String) -> impl Strategy<Value = TextOperation> {
+    let len = doc.chars().count();
+    proptest::collection::vec((0..3u8, 1..4usize, "[a-z]{1,3}"), 0..8).prop_map(move |steps| {
+        let mut op = TextOperation::new();
+        let mut remaining = len;
+        for (kind, n, text) in steps {
+            let n = n.min(remaining);
+            op = match kind {
+                0 => op.retain(n),
+                1 => op.delete(n),
+                _ => {
+                    op = op.insert(&text);
+                    continue;
+                }
+            };
+            remaining -= n;
+        }
+        op.retain(remaining)
+    })
+}
+
+proptest! {
+    #[test]
+    fn transform_satisfies_tp1((doc, op_a, op_b) in concurrent_pair()) {
+        let left = apply(&doc, &[vec![op_a.clone()], OT::transform(&op_b, &op_a)].concat());
+        let right = apply(&doc, &[vec![op_b.clone()], OT::transform(&op_a, &op_b)].concat());
+        prop_assert_eq!(left, right);
+    }
+
+    #[test]
+    fn text_operation_transform_satisfies_tp1(
+        (doc, a, b) in "[A-Z]{0,12}".prop_flat_map(|doc| {
+            (Just(doc.clone()), text_operation(doc.clone()), text_operation(doc))
+        })
+    ) {
+        let (a_prime, b_prime) = TextOperation::transform(&a, &b).unwrap();
+        let left = b_prime.apply(&a.apply(&doc).unwrap()).unwrap();
+        let right = a_prime.apply(&b.apply(&doc).unwrap()).unwrap();
+        prop_assert_eq!(left, right);
+    }
+}